@@ -0,0 +1,34 @@
+//! Run with `cargo bench --bench signature`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use podman_autoupdate_hook::signature::Verifier;
+
+const SECRETS: [&str; 3] = ["current", "previous", "secondary"];
+
+fn verify(c: &mut Criterion) {
+    let mut group = c.benchmark_group("verify");
+    for size in [1 << 10, 64 << 10, 1 << 20, 16 << 20] {
+        let body = vec![b'x'; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        for secrets in [1, SECRETS.len()] {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{} secrets", secrets), size),
+                &body,
+                |b, body| {
+                    b.iter(|| {
+                        // the handler hashes the body as it streams in, one chunk at a time
+                        let mut verifier = Verifier::new(&SECRETS[..secrets]);
+                        for chunk in body.chunks(8 << 10) {
+                            verifier.update(chunk);
+                        }
+                        verifier.verify("0000")
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, verify);
+criterion_main!(benches);
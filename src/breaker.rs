@@ -0,0 +1,90 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Stops running updates for a while after too many of them failed in a row,
+/// e.g. because the registry is down.
+pub struct Breaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<Failures>,
+}
+
+#[derive(Default)]
+struct Failures {
+    consecutive: u32,
+    open_until: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BreakerState {
+    /// Updates run normally.
+    Closed,
+    /// Updates are rejected until the cooldown is over.
+    Open,
+    /// The cooldown is over and the next update decides whether to close or
+    /// reopen the breaker.
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half-open",
+        }
+    }
+}
+
+impl Breaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Breaker {
+            threshold,
+            cooldown,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match self.state.lock().unwrap().open_until {
+            None => BreakerState::Closed,
+            Some(until) if Instant::now() < until => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// How long until updates may run again, if the breaker is open.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.state
+            .lock()
+            .unwrap()
+            .open_until
+            .and_then(|until| until.checked_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Records the outcome of an update. Any success closes the breaker, and a
+    /// failure while half-open opens it again straight away.
+    pub fn record(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        if success {
+            if state.open_until.is_some() {
+                tracing::info!("update succeeded, closing the circuit breaker");
+            }
+            *state = Failures::default();
+            return;
+        }
+
+        state.consecutive += 1;
+        if state.consecutive >= self.threshold {
+            tracing::warn!(
+                "{} updates failed in a row, rejecting updates for {:?}",
+                state.consecutive,
+                self.cooldown
+            );
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! A pool of the buffers webhook bodies are read into, so a burst of webhooks
+//! reuses a few allocations instead of making one each. Only small buffers
+//! are kept, so a large payload doesn't stay in memory after its request.
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+use bytes::BytesMut;
+
+pub struct BufferPool {
+    idle: Mutex<Vec<BytesMut>>,
+    max_buffers: usize,
+    max_capacity: usize,
+}
+
+impl BufferPool {
+    /// Keeps up to `max_buffers` idle buffers of at most `max_capacity` bytes.
+    pub fn new(max_buffers: usize, max_capacity: usize) -> Self {
+        BufferPool {
+            idle: Mutex::new(Vec::with_capacity(max_buffers)),
+            max_buffers,
+            max_capacity,
+        }
+    }
+
+    /// An empty buffer, which goes back to the pool when it is dropped. It
+    /// only has room for what an earlier request left it with.
+    pub fn take(&self) -> Buffer<'_> {
+        let bytes = self.idle.lock().unwrap().pop().unwrap_or_default();
+        Buffer { bytes, pool: self }
+    }
+
+    /// How many buffers are waiting to be reused.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+/// A buffer taken from a [`BufferPool`].
+pub struct Buffer<'a> {
+    bytes: BytesMut,
+    pool: &'a BufferPool,
+}
+
+impl Deref for Buffer<'_> {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.bytes
+    }
+}
+
+impl DerefMut for Buffer<'_> {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.bytes
+    }
+}
+
+impl Drop for Buffer<'_> {
+    fn drop(&mut self) {
+        if self.bytes.capacity() > self.pool.max_capacity {
+            return;
+        }
+        let mut idle = self.pool.idle.lock().unwrap();
+        if idle.len() < self.pool.max_buffers {
+            let mut bytes = std::mem::take(&mut self.bytes);
+            bytes.clear();
+            idle.push(bytes);
+        }
+    }
+}
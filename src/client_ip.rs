@@ -0,0 +1,50 @@
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The reverse proxies whose `X-Forwarded-For` header is believed.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct TrustedProxies(pub Vec<IpNet>);
+
+impl TrustedProxies {
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The address of the client that made a request.
+    ///
+    /// Connections from untrusted peers are taken at face value, since anyone
+    /// can set `X-Forwarded-For`. For trusted peers the header is walked from
+    /// the right (the hop closest to us) and the first untrusted address is
+    /// the client. If every hop is trusted the leftmost one is used, and an
+    /// unparseable hop stops the walk at the last address we could trust.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusts(peer) {
+            return peer;
+        }
+
+        let hops: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            match hop.parse() {
+                Ok(ip) => {
+                    client = ip;
+                    if !self.trusts(ip) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        client
+    }
+}
@@ -0,0 +1,155 @@
+use serde::Deserialize;
+use std::{collections::HashMap, fmt, path::Path};
+
+use crate::TokenCommand;
+
+/// Settings loaded from the `--config` file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Additional hooks, each served on `/hook/<name>`.
+    #[serde(default)]
+    pub endpoints: Vec<EndpointConfig>,
+    /// Per-container update policies, keyed by container name or image.
+    #[serde(default)]
+    pub containers: HashMap<String, ContainerPolicy>,
+    /// What every hook does for each github event, keyed by the event name or
+    /// `*` for any other event.
+    #[serde(default)]
+    pub actions: HashMap<String, EventAction>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContainerPolicy {
+    /// Update the container as soon as a newer image is found.
+    #[default]
+    Immediate,
+    /// Only report that a newer image is available.
+    DryRunOnly,
+    /// Leave the container alone and omit it from the response.
+    Ignore,
+}
+
+/// What a hook does when it receives a github event.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventAction {
+    /// Run `podman auto-update`.
+    AutoUpdate,
+    /// Only send a notification that the event arrived.
+    Notify,
+    /// Accept the webhook without doing anything.
+    Skip,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EndpointConfig {
+    pub name: String,
+    pub auth: Option<TokenCommand>,
+    /// Extra arguments passed to `podman auto-update` for this endpoint.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Event actions for this endpoint, taking precedence over the top level
+    /// `[actions]`.
+    #[serde(default)]
+    pub actions: HashMap<String, EventAction>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "failed to read config: {}", e),
+            ConfigError::Parse(e) => write!(f, "failed to parse config: {}", e),
+            ConfigError::Invalid(e) => write!(f, "invalid config: {}", e),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let contents = expand_env(&contents)?;
+        let config: Config = toml::from_str(&contents).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (i, endpoint) in self.endpoints.iter().enumerate() {
+            let valid = !endpoint.name.is_empty()
+                && endpoint
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                return Err(ConfigError::Invalid(format!(
+                    "endpoint name {:?} must be non-empty and only contain [A-Za-z0-9_-]",
+                    endpoint.name
+                )));
+            }
+
+            if let Some(TokenCommand::Github {
+                secondary_secret,
+                secondary_secret_expires,
+                ..
+            }) = &endpoint.auth
+            {
+                if secondary_secret.is_some() != secondary_secret_expires.is_some() {
+                    return Err(ConfigError::Invalid(format!(
+                        "endpoint {:?} must set secondary_secret and secondary_secret_expires together",
+                        endpoint.name
+                    )));
+                }
+            }
+
+            if self.endpoints[..i].iter().any(|e| e.name == endpoint.name) {
+                return Err(ConfigError::Invalid(format!(
+                    "duplicate endpoint name {:?}",
+                    endpoint.name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces every `${VAR}` with the value of the environment variable, so
+/// secrets can be kept out of the file. This happens before the file is
+/// parsed, so comments are expanded too; `$${` is a literal `${`.
+fn expand_env(contents: &str) -> Result<String, ConfigError> {
+    let mut expanded = String::with_capacity(contents.len());
+    let mut rest = contents;
+    while let Some(start) = rest.find("${") {
+        if let Some(before) = rest[..start].strip_suffix('$') {
+            expanded.push_str(before);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let end = rest[start..].find('}').ok_or_else(|| {
+            ConfigError::Invalid(format!(
+                "unterminated variable reference {:?}",
+                &rest[start..]
+            ))
+        })?;
+        let name = &rest[start + 2..start + end];
+        let value = std::env::var(name).map_err(|_| {
+            ConfigError::Invalid(format!("environment variable {:?} is not set", name))
+        })?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
@@ -0,0 +1,53 @@
+//! Remembers when each image was last updated, so a burst of webhooks for
+//! the same image only pulls it once.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How many images are remembered. Past this, expired entries are dropped
+/// first and then the oldest ones.
+const MAX_IMAGES: usize = 1024;
+
+pub struct ImageCooldown {
+    cooldown: Duration,
+    pulls: Mutex<HashMap<String, Instant>>,
+}
+
+impl ImageCooldown {
+    pub fn new(cooldown: Duration) -> Self {
+        ImageCooldown {
+            cooldown,
+            pulls: Mutex::default(),
+        }
+    }
+
+    /// How long ago the image was updated, if that was within the cooldown.
+    pub fn pulled_within(&self, image: &str) -> Option<Duration> {
+        self.pulls
+            .lock()
+            .unwrap()
+            .get(image)
+            .map(Instant::elapsed)
+            .filter(|elapsed| *elapsed < self.cooldown)
+    }
+
+    pub fn record(&self, image: String) {
+        let mut pulls = self.pulls.lock().unwrap();
+        if pulls.len() >= MAX_IMAGES && !pulls.contains_key(&image) {
+            pulls.retain(|_, pulled| pulled.elapsed() < self.cooldown);
+            if pulls.len() >= MAX_IMAGES {
+                let oldest = pulls
+                    .iter()
+                    .min_by_key(|(_, pulled)| **pulled)
+                    .map(|(image, _)| image.clone());
+                if let Some(oldest) = oldest {
+                    pulls.remove(&oldest);
+                }
+            }
+        }
+        pulls.insert(image, Instant::now());
+    }
+}
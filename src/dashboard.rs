@@ -0,0 +1,166 @@
+//! A small HTML status page for checking on the hook from a browser, and the
+//! same state as JSON for monitoring.
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime},
+};
+
+use crate::{podman::AutoUpdateReponse, Shared};
+
+/// The result of the most recent successful update.
+pub struct LastUpdate {
+    pub at: SystemTime,
+    pub containers: Vec<AutoUpdateReponse>,
+}
+
+/// Serves the dashboard to clients on this machine only, since it lists every
+/// container and has no authentication of its own.
+pub async fn dashboard(
+    State(shared): State<Arc<Shared>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    if !is_local(&shared, peer, &headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let version = shared.runner.version().await.unwrap_or_else(|e| {
+        tracing::warn!("failed to get the engine version: {}", e);
+        "unknown".to_owned()
+    });
+    let page = render(
+        &version,
+        shared.last_update.lock().unwrap().as_ref(),
+        shared.running.load(Ordering::SeqCst),
+        shared.waiting.load(Ordering::SeqCst),
+    );
+    Html(page).into_response()
+}
+
+#[derive(Serialize)]
+struct StatusBody<'a> {
+    last_update: Option<String>,
+    age_seconds: Option<u64>,
+    stale: bool,
+    containers: &'a [AutoUpdateReponse],
+}
+
+/// The last update as JSON, served to the same clients as the dashboard. Once
+/// `--status-stale-after` has passed since the last update (or since startup,
+/// before the first one) it is marked stale.
+pub async fn status(
+    State(shared): State<Arc<Shared>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    if !is_local(&shared, peer, &headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let last = shared.last_update.lock().unwrap();
+    let age = last
+        .as_ref()
+        .map(|last| last.at.elapsed().unwrap_or_default());
+    let quiet_for = age.unwrap_or_else(|| shared.started.elapsed());
+    let stale = shared.stale_after.is_some_and(|after| quiet_for > after);
+
+    let body = StatusBody {
+        last_update: last
+            .as_ref()
+            .map(|last| humantime::format_rfc3339_seconds(last.at).to_string()),
+        age_seconds: age.map(|age| age.as_secs()),
+        stale,
+        containers: last.as_ref().map_or(&[], |last| &last.containers[..]),
+    };
+    let status = match stale {
+        true => shared.stale_status,
+        false => StatusCode::OK,
+    };
+    (status, Json(body)).into_response()
+}
+
+/// Whether a request comes from this machine, looking through trusted proxies.
+pub fn is_local(shared: &Shared, peer: SocketAddr, headers: &HeaderMap) -> bool {
+    let client = shared.proxies.client_ip(peer.ip(), headers);
+    let local = client.to_canonical().is_loopback();
+    if !local {
+        tracing::debug!("{} isn't a local client, rejecting", client);
+    }
+    local
+}
+
+fn render(version: &str, last: Option<&LastUpdate>, running: usize, waiting: usize) -> String {
+    let mut page = String::from(concat!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n",
+        "<title>podman-autoupdate-hook</title>\n",
+        "<style>body{font-family:sans-serif;margin:2em}",
+        "table{border-collapse:collapse}",
+        "th,td{padding:.25em 1em;text-align:left;border-bottom:1px solid #ddd}</style>\n",
+        "</head>\n<body>\n<h1>podman-autoupdate-hook</h1>\n<dl>\n",
+    ));
+
+    let _ = writeln!(page, "<dt>Engine</dt><dd>{}</dd>", escape(version));
+    let _ = writeln!(
+        page,
+        "<dt>Updates</dt><dd>{} running, {} waiting</dd>",
+        running, waiting
+    );
+
+    let Some(last) = last else {
+        page.push_str("<dt>Last update</dt><dd>never</dd>\n</dl>\n</body>\n</html>\n");
+        return page;
+    };
+
+    // whole seconds read better than humantime's nanoseconds
+    let ago = Duration::from_secs(last.at.elapsed().unwrap_or_default().as_secs());
+    let _ = writeln!(
+        page,
+        "<dt>Last update</dt><dd>{} ({} ago)</dd>",
+        humantime::format_rfc3339_seconds(last.at),
+        humantime::format_duration(ago)
+    );
+    let changed: Vec<_> = last
+        .containers
+        .iter()
+        .filter(|c| c.changed())
+        .map(|c| escape(&c.container_name))
+        .collect();
+    let _ = writeln!(
+        page,
+        "<dt>Changed</dt><dd>{}</dd>\n</dl>",
+        match changed.is_empty() {
+            true => "none".to_owned(),
+            false => changed.join(", "),
+        }
+    );
+
+    page.push_str("<table>\n<tr><th>Container</th><th>Image</th><th>Updated</th></tr>\n");
+    for container in &last.containers {
+        let _ = writeln!(
+            page,
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&container.container_name),
+            escape(&container.image),
+            container.updated.as_str()
+        );
+    }
+    page.push_str("</table>\n</body>\n</html>\n");
+    page
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,40 @@
+//! Why a webhook was rejected, sent back as `{"error": "..."}` so a sender
+//! can tell a bad signature from a bad payload.
+
+use std::borrow::Cow;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+#[derive(Debug)]
+pub struct HookError {
+    status: StatusCode,
+    message: Cow<'static, str>,
+}
+
+impl HookError {
+    pub fn new(status: StatusCode, message: impl Into<Cow<'static, str>>) -> Self {
+        HookError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<Cow<'static, str>>) -> Self {
+        HookError::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn unauthorized(message: impl Into<Cow<'static, str>>) -> Self {
+        HookError::new(StatusCode::UNAUTHORIZED, message)
+    }
+}
+
+impl IntoResponse for HookError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
@@ -0,0 +1,45 @@
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::config::ConfigError;
+
+/// The claims github app tokens must carry. `exp` is checked by the
+/// validation itself.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    iss: String,
+}
+
+/// Reads the PEM encoded RSA public key that app tokens are signed with.
+pub fn load_key(path: &Path) -> Result<DecodingKey, ConfigError> {
+    let pem = std::fs::read(path).map_err(|e| {
+        ConfigError::Invalid(format!(
+            "failed to read github app key {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    DecodingKey::from_rsa_pem(&pem).map_err(|e| {
+        ConfigError::Invalid(format!(
+            "failed to parse github app key {}: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// Checks that `token` is an unexpired RS256 JWT signed by `key` and issued
+/// by `issuer`.
+pub fn verify(
+    key: &DecodingKey,
+    issuer: &str,
+    token: &str,
+) -> Result<(), jsonwebtoken::errors::Error> {
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_issuer(&[issuer]);
+    validation.set_required_spec_claims(&["exp", "iss"]);
+    let claims = jsonwebtoken::decode::<Claims>(token, key, &validation)?.claims;
+    tracing::debug!("github app token issued by {}", claims.iss);
+    Ok(())
+}
@@ -3,11 +3,14 @@ use axum::{
     http::HeaderValue,
 };
 
-pub struct GithubSignature256(pub String);
+/// The header github signs deliveries in, unless an endpoint overrides it.
+pub static GITHUB_SIGNATURE_256: HeaderName = HeaderName::from_static("x-hub-signature-256");
 
-impl Header for GithubSignature256 {
+pub struct GithubEvent(pub String);
+
+impl Header for GithubEvent {
     fn name() -> &'static axum::headers::HeaderName {
-        static SIGNATURE_HEADER: HeaderName = HeaderName::from_static("x-hub-signature-256");
+        static SIGNATURE_HEADER: HeaderName = HeaderName::from_static("x-github-event");
         &SIGNATURE_HEADER
     }
 
@@ -20,7 +23,7 @@ impl Header for GithubSignature256 {
             .next()
             .map(|v| {
                 let v = v.to_str().map_err(|_| Error::invalid())?;
-                Ok(GithubSignature256(v.to_string()))
+                Ok(GithubEvent(v.to_string()))
             })
             .unwrap_or(Err(Error::invalid()))
     }
@@ -30,11 +33,42 @@ impl Header for GithubSignature256 {
     }
 }
 
-pub struct GithubEvent(pub String);
+/// The id of the webhook that sent a delivery.
+pub struct GithubHookId(pub String);
 
-impl Header for GithubEvent {
+impl Header for GithubHookId {
     fn name() -> &'static axum::headers::HeaderName {
-        static SIGNATURE_HEADER: HeaderName = HeaderName::from_static("x-github-event");
+        static HOOK_ID_HEADER: HeaderName = HeaderName::from_static("x-github-hook-id");
+        &HOOK_ID_HEADER
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, Error>
+    where
+        Self: Sized,
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        values
+            .next()
+            .map(|v| {
+                let v = v.to_str().map_err(|_| Error::invalid())?;
+                Ok(GithubHookId(v.to_string()))
+            })
+            .unwrap_or(Err(Error::invalid()))
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        if let Ok(value) = HeaderValue::from_str(&self.0) {
+            values.extend(std::iter::once(value));
+        }
+    }
+}
+
+/// The signature the hook attaches to its own responses.
+pub struct HookSignature(pub String);
+
+impl Header for HookSignature {
+    fn name() -> &'static axum::headers::HeaderName {
+        static SIGNATURE_HEADER: HeaderName = HeaderName::from_static("x-podman-hook-signature");
         &SIGNATURE_HEADER
     }
 
@@ -47,12 +81,14 @@ impl Header for GithubEvent {
             .next()
             .map(|v| {
                 let v = v.to_str().map_err(|_| Error::invalid())?;
-                Ok(GithubEvent(v.to_string()))
+                Ok(HookSignature(v.to_string()))
             })
             .unwrap_or(Err(Error::invalid()))
     }
 
-    fn encode<E: Extend<HeaderValue>>(&self, _values: &mut E) {
-        unimplemented!()
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        if let Ok(value) = HeaderValue::from_str(&self.0) {
+            values.extend(std::iter::once(value));
+        }
     }
 }
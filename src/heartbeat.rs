@@ -0,0 +1,53 @@
+//! Best-effort pings to a dead man's switch such as healthchecks.io, which
+//! alerts when they stop arriving.
+
+use std::time::Duration;
+
+/// How long a ping may take before it is given up on.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct Pinger {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Pinger {
+    pub fn new(url: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .expect("the tls backend is available");
+        Pinger { client, url }
+    }
+
+    /// Pings the url in the background. Failures are only logged.
+    pub fn ping(&self) {
+        let pinger = self.clone();
+        tokio::spawn(async move { pinger.send().await });
+    }
+
+    /// Pings the url every `interval`, starting straight away, for as long as
+    /// the hook runs.
+    pub async fn every(self, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        // a slow ping shouldn't be followed by a burst of them
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            self.send().await;
+        }
+    }
+
+    async fn send(&self) {
+        let result = self
+            .client
+            .get(&self.url)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        if let Err(e) = result {
+            tracing::warn!("failed to ping {}: {}", self.url, e);
+        }
+    }
+}
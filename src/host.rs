@@ -0,0 +1,40 @@
+//! Which machine the hook runs on, for telling results from a fleet apart.
+
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct Host {
+    pub hostname: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<String>,
+}
+
+impl Host {
+    /// Reads the hostname, and the systemd machine id if asked to.
+    pub fn read(machine_id: bool) -> Self {
+        let machine_id = machine_id
+            .then(|| match std::fs::read_to_string("/etc/machine-id") {
+                Ok(id) => Some(id.trim().to_owned()),
+                Err(e) => {
+                    tracing::warn!("failed to read /etc/machine-id: {}", e);
+                    None
+                }
+            })
+            .flatten();
+        Host {
+            hostname: hostname(),
+            machine_id,
+        }
+    }
+}
+
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length, and gethostname
+    // truncates rather than overflowing it
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "unknown host".to_owned();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
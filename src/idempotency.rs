@@ -0,0 +1,156 @@
+//! Replays the response of a completed update to a sender that retries it
+//! with the same `Idempotency-Key`, instead of running podman again.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::{header::HeaderName, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+
+use crate::metrics::SkippedWebhook;
+
+/// How many keys are remembered. Past this, expired entries are dropped
+/// first and then the oldest ones.
+const MAX_KEYS: usize = 1024;
+
+/// Set on a response that was replayed from the cache.
+const REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+/// A response that can be kept and sent again.
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// Whether the response was a skipped webhook, which is kept in the
+    /// response's extensions (that can't be copied).
+    pub skipped: bool,
+}
+
+impl Reply {
+    /// Reads the body of a response so it can be kept.
+    pub async fn buffer(response: Response) -> Self {
+        let (parts, body) = response.into_parts();
+        Reply {
+            status: parts.status,
+            headers: parts.headers,
+            body: hyper::body::to_bytes(body).await.unwrap_or_default(),
+            skipped: parts.extensions.get::<SkippedWebhook>().is_some(),
+        }
+    }
+}
+
+impl IntoResponse for Reply {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, self.headers, self.body).into_response();
+        if self.skipped {
+            response.extensions_mut().insert(SkippedWebhook);
+        }
+        response
+    }
+}
+
+enum Entry {
+    /// The first request with the key is still updating.
+    InFlight,
+    Done(Reply),
+}
+
+pub struct IdempotencyCache {
+    retention: Duration,
+    entries: Mutex<HashMap<String, (Instant, Entry)>>,
+}
+
+/// What to do with a request carrying a key.
+pub enum Claimed<'a> {
+    /// The key is new, so the update runs and its reply is stored with
+    /// [`Claim::finish`].
+    New(Claim<'a>),
+    /// The key was seen before, and this is that request's response.
+    Replay(Response),
+    /// A request with the key is still being handled.
+    InFlight,
+}
+
+impl IdempotencyCache {
+    pub fn new(retention: Duration) -> Self {
+        IdempotencyCache {
+            retention,
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn claim(&self, key: &str) -> Claimed<'_> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((at, entry)) if at.elapsed() < self.retention => {
+                return match entry {
+                    Entry::InFlight => Claimed::InFlight,
+                    Entry::Done(reply) => {
+                        let mut response = reply.clone().into_response();
+                        response
+                            .headers_mut()
+                            .insert(REPLAYED, HeaderValue::from_static("true"));
+                        Claimed::Replay(response)
+                    }
+                };
+            }
+            _ => {}
+        }
+
+        if entries.len() >= MAX_KEYS && !entries.contains_key(key) {
+            entries.retain(|_, (at, _)| at.elapsed() < self.retention);
+            if entries.len() >= MAX_KEYS {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (at, _))| *at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key.to_owned(), (Instant::now(), Entry::InFlight));
+        Claimed::New(Claim {
+            cache: self,
+            key: Some(key.to_owned()),
+        })
+    }
+}
+
+/// A key whose update is running. If it is dropped without finishing, e.g.
+/// because the client went away, the key is released so a retry can run.
+pub struct Claim<'a> {
+    cache: &'a IdempotencyCache,
+    key: Option<String>,
+}
+
+impl Claim<'_> {
+    /// Stores the reply for repeats of the key. Without one (the update
+    /// failed) the key is released instead, so a retry runs it again.
+    pub fn finish(mut self, reply: Option<&Reply>) {
+        // dropping the claim releases the key
+        let (Some(reply), Some(key)) = (reply, self.key.take()) else {
+            return;
+        };
+        self.cache
+            .entries
+            .lock()
+            .unwrap()
+            .insert(key, (Instant::now(), Entry::Done(reply.clone())));
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = &self.key {
+            self.cache.entries.lock().unwrap().remove(key);
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::fmt;
+
+use serde::Serialize;
+
+/// The parts of an image reference like `docker.io/library/nginx:1.25`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageReference {
+    pub registry: String,
+    pub repository: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+}
+
+impl ImageReference {
+    /// Splits a reference the way podman and docker resolve short names: the
+    /// registry defaults to `docker.io` (where single names live under
+    /// `library/`), and the tag defaults to `latest` unless a digest pins the
+    /// image instead.
+    pub fn parse(image: &str) -> Self {
+        let (name, digest) = match image.split_once('@') {
+            Some((name, digest)) => (name, Some(digest.to_owned())),
+            None => (image, None),
+        };
+
+        // a tag can only follow the last path component, since a registry
+        // may have a port
+        let last = name.rfind('/').map_or(0, |i| i + 1);
+        let (name, tag) = match name[last..].split_once(':') {
+            Some((_, tag)) => (&name[..name.len() - tag.len() - 1], Some(tag.to_owned())),
+            None => (name, None),
+        };
+        let tag = tag.or_else(|| digest.is_none().then(|| "latest".to_owned()));
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((registry, repository))
+                if registry.contains(['.', ':']) || registry == "localhost" =>
+            {
+                (registry.to_owned(), repository.to_owned())
+            }
+            _ => ("docker.io".to_owned(), name.to_owned()),
+        };
+        let repository = if registry == "docker.io" && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository
+        };
+
+        ImageReference {
+            registry,
+            repository,
+            tag,
+            digest,
+        }
+    }
+}
+
+impl fmt::Display for ImageReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+//! Structured records of each update in the systemd journal, so they can be
+//! filtered with e.g. `journalctl RESULT=failure` or `journalctl CONTAINER=web`.
+
+use libsystemd::logging::{journal_send, Priority};
+
+use crate::podman::{AutoUpdateReponse, Updated};
+
+/// Sends the outcome of an update to the journal, alongside the usual log
+/// line. A journal that can't be reached is only logged.
+pub fn record(event: Option<&str>, result: Result<&[AutoUpdateReponse], &str>) {
+    let updated: Vec<_> = match result {
+        Ok(response) => response
+            .iter()
+            .filter(|c| c.updated == Updated::True)
+            .map(|c| c.container_name.as_str())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    let (priority, message, outcome) = match result {
+        Ok(_) => (
+            Priority::Info,
+            format!("updated {} containers", updated.len()),
+            "success",
+        ),
+        Err(e) => (Priority::Error, format!("update failed: {}", e), "failure"),
+    };
+
+    let count = updated.len().to_string();
+    let mut fields = vec![
+        ("SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME")),
+        ("RESULT", outcome),
+        ("CONTAINERS_UPDATED", count.as_str()),
+    ];
+    fields.extend(event.map(|event| ("EVENT", event)));
+    // a field may repeat, and journalctl matches any of its values
+    fields.extend(updated.iter().map(|name| ("CONTAINER", *name)));
+
+    if let Err(e) = journal_send(priority, &message, fields.into_iter()) {
+        tracing::warn!("failed to write to the journal: {}", e);
+    }
+}
@@ -0,0 +1,65 @@
+//! GitHub webhook secrets kept in a file, so they can be rotated without
+//! restarting the hook.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use crate::config::ConfigError;
+
+/// The secrets in a keyring file, one per line. Blank lines are skipped.
+pub struct Keyring {
+    path: PathBuf,
+    keys: RwLock<Arc<Vec<String>>>,
+}
+
+impl Keyring {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let keys = read(path).map_err(|e| {
+            ConfigError::Invalid(format!("failed to read keyring {}: {}", path.display(), e))
+        })?;
+        if keys.is_empty() {
+            tracing::warn!("keyring {} has no keys", path.display());
+        }
+
+        Ok(Keyring {
+            path: path.to_owned(),
+            keys: RwLock::new(Arc::new(keys)),
+        })
+    }
+
+    /// The current keys. A reload doesn't affect a request that already has
+    /// them.
+    pub fn keys(&self) -> Arc<Vec<String>> {
+        self.keys.read().unwrap().clone()
+    }
+
+    /// Reads the file again, keeping the old keys if that fails.
+    pub fn reload(&self) {
+        match read(&self.path) {
+            Ok(keys) => {
+                tracing::info!(
+                    "reloaded keyring {} with {} keys",
+                    self.path.display(),
+                    keys.len()
+                );
+                *self.keys.write().unwrap() = Arc::new(keys);
+            }
+            Err(e) => tracing::error!(
+                "failed to reload keyring {}, keeping the old keys: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+fn read(path: &Path) -> std::io::Result<Vec<String>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
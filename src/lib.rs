@@ -0,0 +1,2644 @@
+//! A webhook server that runs `podman auto-update`. The binary is a thin
+//! wrapper around [`build_app`].
+
+mod breaker;
+pub mod buffer;
+mod client_ip;
+pub mod config;
+mod cooldown;
+mod dashboard;
+mod error;
+mod github_app;
+mod headers;
+mod heartbeat;
+mod host;
+mod idempotency;
+mod image;
+#[cfg(feature = "journal")]
+mod journal;
+mod keyring;
+pub mod lock;
+mod metrics;
+mod notify;
+mod output;
+mod podman;
+mod push;
+mod registry;
+mod secrets;
+pub mod signature;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod window;
+
+use axum::{
+    error_handling::HandleErrorLayer,
+    extract::{BodyStream, ConnectInfo, RawQuery, State},
+    headers::{authorization::Bearer, Authorization, HeaderMapExt, UserAgent},
+    http::{
+        header::{HeaderName, ACCEPT, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER},
+        HeaderMap, HeaderValue, Request, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router, TypedHeader,
+};
+use breaker::Breaker;
+use buffer::BufferPool;
+use bytes::Bytes;
+use clap::{Parser, Subcommand, ValueEnum};
+use client_ip::TrustedProxies;
+use config::{Config, ConfigError, ContainerPolicy, EventAction};
+use cooldown::ImageCooldown;
+use dashboard::{dashboard, status, LastUpdate};
+use error::HookError;
+use futures_util::StreamExt;
+use headers::{GithubEvent, GithubHookId, HookSignature, GITHUB_SIGNATURE_256};
+use heartbeat::Pinger;
+use hmac::{Hmac, Mac};
+use host::Host;
+use idempotency::{Claimed, IdempotencyCache, Reply};
+use image::ImageReference;
+use ipnet::IpNet;
+use jsonwebtoken::DecodingKey;
+use keyring::Keyring;
+use metrics::{count_webhooks, metrics, Metrics, Scrapers, SkippedWebhook};
+use notify::{Notifier, Target};
+use output::Format;
+pub use podman::Retry;
+use podman::{ApiClient, AutoUpdateReponse, Cli, Runner, Skipped, Update, Updated};
+use registry::RegistryProbe;
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use signature::Verifier;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    io::IsTerminal,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use subtle::ConstantTimeEq;
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    sync::{broadcast, Semaphore, SemaphorePermit},
+};
+use tower::{BoxError, ServiceBuilder};
+use tower_governor::{
+    errors::display_error, governor::GovernorConfigBuilder, key_extractor::KeyExtractor,
+    GovernorError, GovernorLayer,
+};
+use tracing::Instrument;
+use window::Window;
+
+/// How long clients are asked to wait before retrying a rejected update.
+const RETRY_AFTER_SECS: u64 = 30;
+
+/// How many idle body buffers are kept for reuse, and how large they may be.
+/// Bodies larger than that get a buffer of their own that is freed with the
+/// request.
+const POOLED_BUFFERS: usize = 16;
+const POOLED_BUFFER_BYTES: usize = 64 * 1024;
+
+/// The routes the hook serves itself (`/` being the dashboard), which no
+/// hook or endpoint may use, whether or not they're enabled.
+const RESERVED_ROUTES: [&str; 6] = [
+    "/",
+    "/health",
+    "/status",
+    "/metrics",
+    "/admin/drain",
+    "/admin/resume",
+];
+
+/// The command line options, which [`build_app`] turns into routes.
+#[derive(Parser)]
+pub struct Opt {
+    #[clap(short, long, default_value_t = 5000)]
+    pub port: u16,
+
+    /// Listen on this address instead of `0.0.0.0:<port>`, repeat to listen on several
+    #[clap(long, value_name = "ADDR")]
+    pub bind: Vec<SocketAddr>,
+
+    /// Serve the main hook here, with the config file's endpoints below it
+    #[clap(long, default_value = "/hook")]
+    hook_path: String,
+
+    /// Serve every route below this prefix, e.g. `/podman` behind a reverse proxy
+    #[clap(long, default_value = "/")]
+    base_path: String,
+
+    /// Enable TCP keepalive on accepted connections with this idle time (e.g. `60s`)
+    #[clap(long, value_parser = humantime::parse_duration)]
+    pub tcp_keepalive: Option<Duration>,
+
+    /// How many connections may have a request in flight at once, others wait for a free slot
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_connections: Option<usize>,
+
+    /// How many threads handle requests and updates, defaults to the number of CPUs
+    #[clap(
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        conflicts_with = "current_thread"
+    )]
+    pub worker_threads: Option<usize>,
+
+    /// Run everything on a single thread, for small hosts
+    #[clap(long)]
+    pub current_thread: bool,
+
+    /// Shut down gracefully after no webhook arrived for this long (e.g. `15m`)
+    #[clap(long, alias = "shutdown-on-idle", value_parser = humantime::parse_duration)]
+    pub idle_timeout: Option<Duration>,
+
+    /// Serve https with this PEM certificate chain
+    #[cfg(feature = "tls")]
+    #[clap(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// The PEM private key for `--tls-cert`
+    #[cfg(feature = "tls")]
+    #[clap(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Refuse clients that can't speak at least this TLS version
+    #[cfg(feature = "tls")]
+    #[clap(long, value_enum, default_value = "1.2", requires = "tls_cert")]
+    pub tls_min_version: tls::TlsVersion,
+
+    /// Only offer this cipher suite (e.g. `TLS13_AES_256_GCM_SHA384`), repeat for several
+    #[cfg(feature = "tls")]
+    #[clap(long = "tls-cipher-suite", value_name = "SUITE", requires = "tls_cert")]
+    pub tls_cipher_suites: Vec<String>,
+
+    /// Path to a TOML file defining additional named endpoints
+    #[clap(short, long, env = "PODMAN_AUTOUPDATE_HOOK_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Fail `/health` with a 503 when systemd in the given scope is unreachable
+    #[clap(long, value_enum)]
+    probe_systemd: Option<SystemdScope>,
+
+    /// Skip webhooks arriving within this long of the last successful update (e.g. `30s`)
+    #[clap(long, value_parser = humantime::parse_duration)]
+    cooldown: Option<Duration>,
+
+    /// How long the response to an `Idempotency-Key` is replayed for repeats of the key, `0s` to ignore the header
+    #[clap(long, default_value = "1h", value_parser = humantime::parse_duration)]
+    idempotency_retention: Duration,
+
+    /// Skip webhooks for an image that was updated within this long (e.g. `10m`), going by the payload's `image` or github package url
+    #[clap(long, value_parser = humantime::parse_duration)]
+    per_image_cooldown: Option<Duration>,
+
+    /// The container runtime to update
+    #[clap(long, value_enum, default_value_t = Runtime::Podman)]
+    runtime: Runtime,
+
+    /// Talk to podman over its REST API (`unix:///run/podman/podman.sock` or
+    /// `http://host:port`) instead of running the podman cli
+    #[clap(long, env = "PODMAN_URL", conflicts_with = "podman_socket")]
+    podman_url: Option<String>,
+
+    /// Shorthand for `--podman-url unix://<path>`
+    #[clap(long)]
+    podman_socket: Option<PathBuf>,
+
+    /// Respond with a 409 when podman reports that no container changed
+    #[clap(long)]
+    fail_on_no_update: bool,
+
+    /// The podman binary to run
+    #[clap(long, default_value = "podman")]
+    podman_binary: PathBuf,
+
+    /// Parse podman's table output when it doesn't print JSON (for older podman)
+    #[clap(long)]
+    parse_table_fallback: bool,
+
+    /// Pull images first and only restart containers whose image actually changed
+    #[clap(long)]
+    restart_only_on_change: bool,
+
+    /// Pull every registry image before updating, for mutable tags like `:latest`
+    #[clap(long)]
+    force_pull: bool,
+
+    /// Exit codes of `podman auto-update` that count as success, comma separated
+    #[clap(long, value_delimiter = ',', default_value = "0")]
+    success_exit_codes: Vec<i32>,
+
+    /// Only update containers with this label, e.g. `deploy.group=web` (repeatable)
+    #[clap(long = "filter-label", value_name = "KEY=VALUE")]
+    filter_labels: Vec<String>,
+
+    /// With `--runtime docker`, restart the whole compose project when any image changed
+    #[clap(long)]
+    restart_project_on_change: bool,
+
+    /// Only restart this systemd unit, or units starting with a prefix like `web-*` (repeatable)
+    #[clap(long = "allow-unit", value_name = "UNIT")]
+    allowed_units: Vec<String>,
+
+    /// How many times to retry a failed update
+    #[clap(long, default_value_t = 0)]
+    retries: u32,
+
+    /// The most the first retry waits for, doubling for each retry after it
+    #[clap(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    retry_delay: Duration,
+
+    /// The most a retry waits for, however many came before it
+    #[clap(long, default_value = "60s", value_parser = humantime::parse_duration)]
+    retry_max_delay: Duration,
+
+    /// The working directory for podman (and other spawned commands)
+    #[clap(long)]
+    workdir: Option<PathBuf>,
+
+    /// Extra environment variables for podman, e.g. `--env REGISTRY_AUTH_FILE=/auth.json`
+    #[clap(long = "env", value_name = "KEY=VALUE", value_parser = parse_env)]
+    env: Vec<(String, String)>,
+
+    /// Record the result of every update in this file
+    #[clap(long)]
+    result_file: Option<PathBuf>,
+
+    /// Whether to append to the result file (as JSON lines) or overwrite it
+    #[clap(long, value_enum, default_value_t = ResultFileMode::Append)]
+    result_file_mode: ResultFileMode,
+
+    /// The status returned for webhooks that are skipped by a filter, e.g. 204
+    #[clap(long, default_value = "200", value_parser = parse_success_status)]
+    skip_status: StatusCode,
+
+    /// The status returned when the update found a newer image for any container, e.g. 202
+    #[clap(long, default_value = "200", value_parser = parse_success_status)]
+    changed_status: StatusCode,
+
+    /// The status returned when the update found nothing to change, e.g. 204
+    #[clap(long, default_value = "200", value_parser = parse_success_status)]
+    unchanged_status: StatusCode,
+
+    /// How many updates may run at the same time. Podman doesn't cope with overlapping auto-updates, so only raise this for endpoints that update different containers
+    #[clap(
+        long,
+        default_value_t = 1,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    max_concurrent_updates: usize,
+
+    /// Wait this long before updating, so that a burst of webhooks (e.g. `2s`) runs a single update whose result they all get
+    #[clap(long, value_parser = humantime::parse_duration)]
+    coalesce_window: Option<Duration>,
+
+    /// How often one request is added back to a client's rate limit burst
+    #[clap(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    rate_limit_period: Duration,
+
+    /// How many requests a client can make at once before it is rate limited
+    #[clap(
+        long,
+        default_value_t = 5,
+        value_parser = clap::builder::RangedU64ValueParser::<u32>::new().range(1..)
+    )]
+    rate_limit_burst: u32,
+
+    /// Don't rate limit clients at all, e.g. when only trusted services can reach the hook
+    #[clap(long, conflicts_with_all = ["rate_limit_period", "rate_limit_burst"])]
+    no_rate_limit: bool,
+
+    /// Reject webhook bodies larger than this with a 413, before they are read in full
+    #[clap(long, default_value_t = 1024 * 1024)]
+    max_body_bytes: usize,
+
+    /// Reject requests with a 503 when this many are already waiting to update
+    #[clap(long)]
+    max_inflight_queue: Option<usize>,
+
+    /// Only run updates between these local times (e.g. `02:00-05:00`), deferring webhooks that arrive outside
+    #[clap(long, value_name = "HH:MM-HH:MM")]
+    maintenance_window: Option<Window>,
+
+    /// Sign response bodies with HMAC-SHA256 in the `X-Podman-Hook-Signature` header
+    #[clap(long, env = "PODMAN_AUTOUPDATE_HOOK_RESPONSE_SECRET")]
+    response_secret: Option<String>,
+
+    /// Publish a notification to this ntfy topic url when containers are updated
+    #[clap(long)]
+    ntfy_url: Option<String>,
+
+    /// Post a notification to this gotify server when containers are updated
+    #[clap(long, requires = "gotify_token")]
+    gotify_url: Option<String>,
+
+    /// The gotify application token
+    #[clap(
+        long,
+        env = "PODMAN_AUTOUPDATE_HOOK_GOTIFY_TOKEN",
+        requires = "gotify_url"
+    )]
+    gotify_token: Option<String>,
+
+    /// Post a notification to this slack incoming webhook url when containers are updated
+    #[clap(long, env = "PODMAN_AUTOUPDATE_HOOK_SLACK_WEBHOOK")]
+    slack_webhook: Option<String>,
+
+    /// Post a notification to this discord webhook url when containers are updated
+    #[clap(long, env = "PODMAN_AUTOUPDATE_HOOK_DISCORD_WEBHOOK")]
+    discord_webhook: Option<String>,
+
+    /// Reject updates for `--breaker-cooldown` after this many fail in a row
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<u32>::new().range(1..))]
+    breaker_threshold: Option<u32>,
+
+    /// Ping this url regularly to show the hook is alive, e.g. a healthchecks.io check
+    #[clap(long, value_name = "URL")]
+    heartbeat_url: Option<String>,
+
+    /// How often to ping `--heartbeat-url`
+    #[clap(long, default_value = "60s", value_parser = humantime::parse_duration)]
+    heartbeat_interval: Duration,
+
+    /// Ping this url after every successful update, to show updates are happening
+    #[clap(long, value_name = "URL")]
+    heartbeat_update_url: Option<String>,
+
+    /// How long the circuit breaker rejects updates before trying again
+    #[clap(long, default_value = "60s", value_parser = humantime::parse_duration)]
+    breaker_cooldown: Duration,
+
+    /// Reject payloads without this JSON field (e.g. `image` or `repository.name`) with a 422
+    #[clap(long = "require-json-field", value_name = "PATH")]
+    required_fields: Vec<String>,
+
+    /// Reject requests whose User-Agent doesn't contain this (e.g. `GitHub-Hookshot/`) with a 403
+    #[clap(long, value_name = "SUBSTRING")]
+    require_user_agent: Option<String>,
+
+    /// Add the registry, repository, tag and digest of each image to the response
+    #[clap(long)]
+    image_details: bool,
+
+    /// Add the hostname to the response, and to the logs of every request
+    #[clap(long)]
+    host_info: bool,
+
+    /// Also add the systemd machine id from /etc/machine-id
+    #[clap(long, requires = "host_info")]
+    host_info_machine_id: bool,
+
+    /// Check that this registry (e.g. `ghcr.io`) answers before updating, and fail with a 502 if it doesn't
+    #[clap(long, value_name = "REGISTRY")]
+    probe_registry: Vec<String>,
+
+    /// Wait for the healthchecks of updated containers to pass, and respond with a 207 if any don't
+    #[clap(long)]
+    verify_health: bool,
+
+    /// How long `--verify-health` waits for a container to become healthy
+    #[clap(
+        long,
+        default_value = "60s",
+        value_parser = humantime::parse_duration,
+        requires = "verify_health"
+    )]
+    verify_health_timeout: Duration,
+
+    /// Respond with podman's JSON output exactly as it printed it, instead of re-serializing it
+    #[clap(long)]
+    passthrough: bool,
+
+    /// Run `podman auto-update --dry-run`, reporting what would be updated without pulling or restarting anything
+    #[clap(long)]
+    dry_run: bool,
+
+    /// List the containers that a policy or filter left out of the update in the response
+    #[clap(long)]
+    report_skipped: bool,
+
+    /// Also record each update in the systemd journal with RESULT, EVENT and CONTAINERS_UPDATED fields
+    #[cfg(feature = "journal")]
+    #[clap(long)]
+    journal: bool,
+
+    /// Pretty-print JSON responses
+    #[clap(long)]
+    pretty: bool,
+
+    /// Serve an HTML status page at `/` to clients on this machine
+    #[clap(long)]
+    dashboard: bool,
+
+    /// Serve prometheus metrics about webhooks and updates at `/metrics` to clients on this machine
+    #[clap(long)]
+    metrics: bool,
+
+    /// Also serve `/metrics` to clients in this range (address or CIDR), like a prometheus on another host
+    #[clap(long = "metrics-allow", value_name = "CIDR", value_parser = parse_cidr, requires = "metrics")]
+    metrics_allow: Vec<IpNet>,
+
+    /// Serve the last update as JSON at `/status` to clients on this machine
+    #[clap(long)]
+    status: bool,
+
+    /// Mark `/status` as stale when there was no update for this long (e.g. `1d`)
+    #[clap(long, value_parser = humantime::parse_duration)]
+    status_stale_after: Option<Duration>,
+
+    /// The status code `/status` returns when it is stale, e.g. 503
+    #[clap(long, default_value = "200", value_parser = parse_status)]
+    status_stale_code: StatusCode,
+
+    /// Return at most this many containers, in an object with the total count
+    #[clap(long)]
+    max_response_containers: Option<usize>,
+
+    /// A reverse proxy (address or CIDR) whose `X-Forwarded-For` header is trusted
+    #[clap(long = "trusted-proxy", value_name = "CIDR", value_parser = parse_cidr)]
+    trusted_proxies: Vec<IpNet>,
+
+    /// Enable `/admin/drain` and `/admin/resume`, authenticated with this bearer token
+    #[clap(long, env = "PODMAN_AUTOUPDATE_HOOK_ADMIN_TOKEN")]
+    admin_token: Option<String>,
+
+    /// Run a dry run at startup to catch misconfiguration, and optionally refuse to start if it fails
+    #[clap(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "warn")]
+    pub warmup: Option<Warmup>,
+
+    /// Whether to color log output (`auto` colors it when the log goes to a terminal)
+    #[clap(long, value_enum, default_value_t = Color::Auto)]
+    pub color: Color,
+
+    /// Allow hooks without authentication, which anyone who can reach them can trigger
+    #[clap(long)]
+    insecure_no_auth: bool,
+
+    /// Run one update, print its JSON result to stdout and exit, logging to stderr
+    #[clap(long)]
+    pub once: bool,
+
+    /// Refuse to start while another instance holds a lock on this file
+    #[clap(long)]
+    pub lock_file: Option<PathBuf>,
+
+    /// Log one access line per request, with its method, path, status and duration
+    #[clap(long)]
+    log_requests: bool,
+
+    #[clap(subcommand)]
+    command: Option<TokenCommand>,
+}
+
+#[derive(Subcommand, Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum TokenCommand {
+    Github {
+        secret: String,
+        #[serde(default)]
+        events: Vec<String>,
+        /// Other secrets to accept, e.g. for org and repo webhooks on one endpoint
+        #[clap(long = "additional-secret")]
+        #[serde(default)]
+        additional_secrets: Vec<String>,
+        /// Only act on payloads for these repositories (`owner/name`)
+        #[clap(long = "github-repo")]
+        #[serde(default)]
+        repos: Vec<String>,
+        /// A secret being rotated out, accepted until `--secondary-secret-expires`
+        #[clap(long, requires = "secondary_secret_expires")]
+        #[serde(default)]
+        secondary_secret: Option<String>,
+        /// When the secondary secret stops being accepted, e.g. `2024-01-31T00:00:00Z`
+        #[clap(long, requires = "secondary_secret")]
+        #[serde(default)]
+        secondary_secret_expires: Option<Timestamp>,
+        /// A file of extra secrets to accept, one per line, reread on SIGHUP
+        #[clap(long)]
+        #[serde(default)]
+        secret_keyring: Option<PathBuf>,
+        /// Only act on deliveries from the webhook with this id (`X-GitHub-Hook-ID`)
+        #[clap(long)]
+        #[serde(default)]
+        github_hook_id: Option<String>,
+        /// Read the signature from this header instead of `X-Hub-Signature-256`, e.g. behind a proxy that renames it
+        #[clap(long)]
+        #[serde(default)]
+        signature_header: Option<String>,
+    },
+    Token {
+        bearer: String,
+    },
+    /// Docker hub or ghcr push webhooks, authenticated with a bearer token or a `?token=` query parameter
+    Registry {
+        secret: String,
+        /// Only update when one of these images was pushed; without a tag, any tag matches
+        #[serde(default)]
+        images: Vec<String>,
+    },
+    #[serde(rename = "github-app")]
+    GithubApp {
+        /// The PEM encoded public key that the app signs its tokens with
+        public_key: PathBuf,
+        /// The issuer the tokens must carry (the app's id)
+        issuer: String,
+    },
+}
+
+impl TokenCommand {
+    /// Replaces secrets given as `env://`, `file://` or `vault://` URIs with
+    /// the secrets they point to.
+    async fn resolve_secrets(&mut self) -> Result<(), ConfigError> {
+        match self {
+            TokenCommand::Github {
+                secret,
+                additional_secrets,
+                secondary_secret,
+                ..
+            } => {
+                for secret in std::iter::once(secret)
+                    .chain(additional_secrets)
+                    .chain(secondary_secret)
+                {
+                    *secret = secrets::resolve(secret).await?;
+                }
+            }
+            TokenCommand::Token { bearer } => *bearer = secrets::resolve(bearer).await?,
+            TokenCommand::Registry { secret, .. } => *secret = secrets::resolve(secret).await?,
+            TokenCommand::GithubApp { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Fetches every secret given as a URI, which must happen before
+/// [`build_app`] for those secrets to work.
+pub async fn resolve_secrets(opt: &mut Opt, config: &mut Config) -> Result<(), ConfigError> {
+    let commands = opt
+        .command
+        .iter_mut()
+        .chain(config.endpoints.iter_mut().flat_map(|e| e.auth.as_mut()));
+    for command in commands {
+        command.resolve_secrets().await?;
+    }
+    Ok(())
+}
+
+/// A point in time, written as an RFC 3339 timestamp.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Timestamp(SystemTime);
+
+impl FromStr for Timestamp {
+    type Err = humantime::TimestampError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        humantime::parse_rfc3339_weak(s).map(Timestamp)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        humantime::format_rfc3339_seconds(self.0).fmt(f)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Warmup {
+    /// Log a failed warmup and start anyway
+    Warn,
+    /// Exit if the warmup fails
+    Fail,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    /// Whether logs written to `output` should contain ANSI colors.
+    pub fn enabled(self, output: &impl IsTerminal) -> bool {
+        match self {
+            Color::Auto => output.is_terminal(),
+            Color::Always => true,
+            Color::Never => false,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Eq, PartialEq)]
+enum SystemdScope {
+    System,
+    User,
+}
+
+impl SystemdScope {
+    /// The scope podman uses for units: rootless podman runs in the user's manager.
+    fn current() -> Self {
+        // SAFETY: geteuid is always successful
+        if unsafe { libc::geteuid() } == 0 {
+            SystemdScope::System
+        } else {
+            SystemdScope::User
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Eq, PartialEq)]
+enum Runtime {
+    /// Run `podman auto-update`
+    Podman,
+    /// Run `docker compose pull` and `docker compose up --detach`
+    Docker,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Eq, PartialEq)]
+enum ResultFileMode {
+    Append,
+    Overwrite,
+}
+
+#[derive(Clone)]
+struct Token(Option<TokenCommand>);
+
+/// The state for a single hook route.
+struct Endpoint {
+    token: Token,
+    /// The parsed public key for a `github-app` token.
+    app_key: Option<DecodingKey>,
+    /// The keyring of a `github` token with `--secret-keyring`.
+    keyring: Option<Arc<Keyring>>,
+    /// The header a `github` token's signature is read from.
+    signature_header: HeaderName,
+    args: Vec<String>,
+    /// What to do for each github event, with `*` for any other event.
+    actions: HashMap<String, EventAction>,
+    /// Whether an update is waiting for the maintenance window.
+    deferred: AtomicBool,
+    /// The responses to replay for repeated `Idempotency-Key`s.
+    replies: Option<IdempotencyCache>,
+    /// The update webhooks within `--coalesce-window` are waiting for.
+    batch: Mutex<Option<broadcast::Sender<Batched>>>,
+    shared: Arc<Shared>,
+}
+
+impl Endpoint {
+    /// The action for an event, which is an update unless the config says
+    /// otherwise.
+    fn action(&self, event: Option<&str>) -> EventAction {
+        event
+            .and_then(|event| self.actions.get(event))
+            .or_else(|| self.actions.get("*"))
+            .copied()
+            .unwrap_or(EventAction::AutoUpdate)
+    }
+}
+
+/// State shared by every hook route.
+struct Shared {
+    runner: Runner,
+    policies: HashMap<String, ContainerPolicy>,
+    retry: Retry,
+    cooldown: Option<Duration>,
+    image_cooldown: Option<ImageCooldown>,
+    fail_on_no_update: bool,
+    result_file: Option<(PathBuf, ResultFileMode)>,
+    response_secret: Option<String>,
+    max_response_containers: Option<usize>,
+    required_fields: Vec<String>,
+    required_user_agent: Option<String>,
+    image_details: bool,
+    verify_health: Option<Duration>,
+    host: Option<Arc<Host>>,
+    report_skipped: bool,
+    passthrough: bool,
+    pretty: bool,
+    #[cfg(feature = "journal")]
+    journal: bool,
+    breaker: Option<Breaker>,
+    registry_probe: Option<RegistryProbe>,
+    notifier: Option<Notifier>,
+    update_pinger: Option<Pinger>,
+    skip_status: StatusCode,
+    max_body_bytes: usize,
+    body_buffers: BufferPool,
+    changed_status: StatusCode,
+    unchanged_status: StatusCode,
+    proxies: TrustedProxies,
+    update_permits: Semaphore,
+    coalesce_window: Option<Duration>,
+    max_queue: Option<usize>,
+    window: Option<Window>,
+    waiting: AtomicUsize,
+    running: AtomicUsize,
+    metrics: Metrics,
+    /// Updates waiting for the maintenance window to open.
+    deferred: AtomicUsize,
+    /// When the last webhook arrived, or startup, for `--idle-timeout`.
+    last_webhook: Mutex<Instant>,
+    /// Set during maintenance to reject new updates.
+    draining: AtomicBool,
+    last_success: Mutex<Option<Instant>>,
+    last_update: Mutex<Option<LastUpdate>>,
+    started: Instant,
+    stale_after: Option<Duration>,
+    stale_status: StatusCode,
+}
+
+impl Shared {
+    /// The JSON body for the result of an update. It is an object instead of
+    /// the bare list of containers when there is more to say.
+    fn response_body(&self, update: &Update) -> serde_json::Result<Vec<u8>> {
+        let response = &update.containers;
+        if self.max_response_containers.is_none() && self.host.is_none() && !self.report_skipped {
+            return to_json(response, self.pretty);
+        }
+
+        let max = self.max_response_containers.unwrap_or(response.len());
+        if response.len() > max {
+            tracing::info!(
+                "returning {} of {} containers, the rest are only logged",
+                max,
+                response.len()
+            );
+            tracing::debug!("full update result: {:?}", response);
+        }
+        to_json(
+            &HookResponse {
+                host: self.host.as_deref(),
+                updated: &response[..max.min(response.len())],
+                truncated: response.len() > max,
+                total: response.len(),
+                skipped: self.report_skipped.then_some(&update.skipped[..]),
+            },
+            self.pretty,
+        )
+    }
+}
+
+/// The state for the admin routes.
+struct Admin {
+    token: String,
+    shared: Arc<Shared>,
+}
+
+/// The routes built by [`build_app`], and a way to look at their state.
+pub struct App {
+    pub router: Router,
+    pub status: Status,
+}
+
+/// A view of the hook's state from outside the routes.
+#[derive(Clone)]
+pub struct Status {
+    shared: Arc<Shared>,
+    keyrings: Vec<Arc<Keyring>>,
+}
+
+impl Status {
+    /// The number of updates that are running.
+    pub fn updates_running(&self) -> usize {
+        self.shared.running.load(Ordering::SeqCst)
+    }
+
+    /// The number of requests waiting for their turn to update.
+    pub fn updates_waiting(&self) -> usize {
+        self.shared.waiting.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once no webhook has arrived for `timeout`, as long as no
+    /// update is running, waiting for a turn or deferred.
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let since = self.shared.last_webhook.lock().unwrap().elapsed();
+            let busy = self.updates_running()
+                + self.updates_waiting()
+                + self.shared.deferred.load(Ordering::SeqCst);
+            if since >= timeout && busy == 0 {
+                return;
+            }
+            // check again once the most recent webhook is old enough
+            tokio::time::sleep(timeout.saturating_sub(since).max(Duration::from_secs(1))).await;
+        }
+    }
+
+    /// Does a dry run with the configured runner, and checks that systemd
+    /// can be reached when the podman cli will be restarting units. Problems
+    /// are logged, and the return value says whether there were any.
+    pub async fn warmup(&self) -> bool {
+        let mut ok = true;
+        match self.shared.runner.dry_run(&[]).await {
+            Ok(response) => {
+                let pending = response.iter().filter(|r| r.changed()).count();
+                tracing::info!(
+                    "warmup found {} containers, {} with a newer image",
+                    response.len(),
+                    pending
+                );
+            }
+            Err(e) => {
+                tracing::error!("warmup failed: {}", e);
+                ok = false;
+            }
+        }
+
+        if matches!(self.shared.runner, Runner::Cli(_))
+            && !systemd_reachable(SystemdScope::current()).await
+        {
+            tracing::error!("warmup failed: systemd is unreachable");
+            ok = false;
+        }
+        ok
+    }
+
+    /// Runs one update outside of any webhook, for `--once`, and returns the
+    /// body a webhook to `/hook` would have got.
+    pub async fn update_once(&self) -> Result<Vec<u8>, String> {
+        let shared = &self.shared;
+        if let Runner::Cli(cli) = &shared.runner {
+            if shared.passthrough {
+                return cli
+                    .auto_update_raw(&[], shared.retry)
+                    .await
+                    .map_err(|e| e.to_string());
+            }
+        }
+        let mut update = shared
+            .runner
+            .update(&[], &shared.policies, shared.retry)
+            .await
+            .map_err(|e| e.to_string())?;
+        if shared.image_details {
+            add_image_details(&mut update.containers);
+        }
+        shared.response_body(&update).map_err(|e| e.to_string())
+    }
+
+    /// Whether any endpoint uses a `--secret-keyring`.
+    pub fn has_keyrings(&self) -> bool {
+        !self.keyrings.is_empty()
+    }
+
+    /// Rereads every keyring file, e.g. after a SIGHUP.
+    pub fn reload_keyrings(&self) {
+        for keyring in &self.keyrings {
+            keyring.reload();
+        }
+    }
+}
+
+/// Builds the hook's routes from the command line and the config file,
+/// without binding a socket. Serve it with
+/// `into_make_service_with_connect_info::<SocketAddr>()`, since rate limiting
+/// keys on the client address. With `--heartbeat-url` this spawns the
+/// heartbeat task, so it must be called from within a tokio runtime.
+pub fn build_app(opt: Opt, config: Config) -> Result<App, ConfigError> {
+    let hook_path = normalize_path(&opt.hook_path)?;
+    if RESERVED_ROUTES.contains(&hook_path.as_str()) {
+        return Err(ConfigError::Invalid(format!(
+            "--hook-path {} is taken by another route",
+            hook_path
+        )));
+    }
+    if let Some(endpoint) = config
+        .endpoints
+        .iter()
+        .find(|e| RESERVED_ROUTES.contains(&format!("{}/{}", hook_path, e.name).as_str()))
+    {
+        return Err(ConfigError::Invalid(format!(
+            "endpoint {} at {}/{} is taken by another route",
+            endpoint.name, hook_path, endpoint.name
+        )));
+    }
+    // the routes are nested below the base path, and logged with it
+    let base_path = match normalize_path(&opt.base_path)? {
+        root if root == "/" => String::new(),
+        base => base,
+    };
+    let route = |path: &str| format!("{}{}", base_path, path);
+
+    // --once doesn't serve any hooks, so it doesn't need to authenticate them
+    if !opt.insecure_no_auth && !opt.once {
+        let unauthenticated = match opt.command {
+            None => Some(route(&hook_path)),
+            Some(_) => config
+                .endpoints
+                .iter()
+                .find(|e| e.auth.is_none())
+                .map(|e| route(&format!("{}/{}", hook_path, e.name))),
+        };
+        if let Some(path) = unauthenticated {
+            return Err(ConfigError::Invalid(format!(
+                "{} has no authentication, give it a token (e.g. `token <secret>`) \
+                 or pass --insecure-no-auth to accept anyone",
+                path
+            )));
+        }
+    }
+
+    if let Some(workdir) = &opt.workdir {
+        if !workdir.is_dir() {
+            return Err(ConfigError::Invalid(format!(
+                "workdir {} is not a directory",
+                workdir.display()
+            )));
+        }
+    }
+
+    let podman_url = opt.podman_url.clone().or_else(|| {
+        opt.podman_socket
+            .as_ref()
+            .map(|p| format!("unix://{}", p.display()))
+    });
+    let cli = Cli {
+        podman: opt.podman_binary.clone(),
+        workdir: opt.workdir.clone(),
+        env: opt.env.clone(),
+        parse_table_fallback: opt.parse_table_fallback,
+        restart_only_on_change: opt.restart_only_on_change,
+        force_pull: opt.force_pull,
+        success_exit_codes: opt.success_exit_codes.clone(),
+        filter_labels: opt.filter_labels.clone(),
+        restart_project_on_change: opt.restart_project_on_change,
+        allowed_units: opt.allowed_units.clone(),
+        dry_run: opt.dry_run,
+    };
+    let runner = match (opt.runtime, podman_url.as_deref().map(ApiClient::new)) {
+        (Runtime::Docker, None) => {
+            tracing::info!("using docker compose");
+            Runner::Docker(cli)
+        }
+        (Runtime::Podman, None) => Runner::Cli(cli),
+        (Runtime::Podman, Some(Ok(client))) => {
+            tracing::info!("using podman api at {}", podman_url.unwrap_or_default());
+            Runner::Api(client)
+        }
+        (Runtime::Docker, Some(_)) => {
+            return Err(ConfigError::Invalid(
+                "the podman api can't be used with the docker runtime".to_owned(),
+            ));
+        }
+        (_, Some(Err(e))) => return Err(ConfigError::Invalid(e)),
+    };
+
+    if opt.restart_only_on_change && !matches!(runner, Runner::Cli(_)) {
+        tracing::warn!("--restart-only-on-change only applies to the podman cli");
+    }
+    if opt.force_pull && !matches!(runner, Runner::Cli(_)) {
+        tracing::warn!("--force-pull only applies to the podman cli");
+    }
+    if opt.verify_health && !matches!(runner, Runner::Cli(_)) {
+        tracing::warn!("--verify-health only applies to the podman cli");
+    }
+    if opt.restart_project_on_change && !matches!(runner, Runner::Docker(_)) {
+        tracing::warn!("--restart-project-on-change only applies to --runtime docker");
+    }
+
+    // the api and compose would still restart containers
+    if opt.dry_run && !matches!(runner, Runner::Cli(_)) {
+        return Err(ConfigError::Invalid(
+            "--dry-run only works with the podman cli".to_owned(),
+        ));
+    }
+
+    // ignoring these would restart the containers they're meant to protect
+    if !opt.filter_labels.is_empty() && !matches!(runner, Runner::Cli(_)) {
+        return Err(ConfigError::Invalid(
+            "--filter-label only works with the podman cli".to_owned(),
+        ));
+    }
+    if !opt.allowed_units.is_empty() && !matches!(runner, Runner::Cli(_)) {
+        return Err(ConfigError::Invalid(
+            "--allow-unit only works with the podman cli".to_owned(),
+        ));
+    }
+
+    // compose has already recreated every service by the time a policy could
+    // pick them
+    if matches!(runner, Runner::Docker(_))
+        && config
+            .containers
+            .values()
+            .any(|p| *p != ContainerPolicy::Immediate)
+    {
+        return Err(ConfigError::Invalid(
+            "container policies other than immediate don't work with --runtime docker".to_owned(),
+        ));
+    }
+
+    if opt.passthrough {
+        if !matches!(runner, Runner::Cli(_)) {
+            return Err(ConfigError::Invalid(
+                "--passthrough only works with the podman cli".to_owned(),
+            ));
+        }
+        // these need the parsed output, while passthrough never parses it
+        if !config.containers.is_empty()
+            || opt.restart_only_on_change
+            || opt.force_pull
+            || !opt.filter_labels.is_empty()
+            || !opt.allowed_units.is_empty()
+            || opt.verify_health
+        {
+            return Err(ConfigError::Invalid(
+                "--passthrough runs a plain podman auto-update, so it can't be combined with \
+                 container policies, --restart-only-on-change, --force-pull, --filter-label, \
+                 --allow-unit or --verify-health"
+                    .to_owned(),
+            ));
+        }
+    }
+
+    if matches!(runner, Runner::Api(_)) && config.endpoints.iter().any(|e| !e.args.is_empty()) {
+        tracing::warn!("endpoint args are ignored when using the podman api");
+    }
+
+    if let Some(url) = opt.heartbeat_url {
+        if opt.heartbeat_interval.is_zero() {
+            return Err(ConfigError::Invalid(
+                "--heartbeat-interval must be more than zero".to_owned(),
+            ));
+        }
+        tracing::info!("pinging {} every {:?}", url, opt.heartbeat_interval);
+        tokio::spawn(Pinger::new(url).every(opt.heartbeat_interval));
+    }
+
+    let notifier = Notifier::new(
+        opt.ntfy_url
+            .map(Target::Ntfy)
+            .into_iter()
+            .chain(
+                opt.gotify_url
+                    .zip(opt.gotify_token)
+                    .map(|(url, token)| Target::Gotify { url, token }),
+            )
+            .chain(opt.slack_webhook.map(Target::Slack))
+            .chain(opt.discord_webhook.map(Target::Discord))
+            .collect(),
+    );
+    let notifies = config
+        .actions
+        .values()
+        .chain(config.endpoints.iter().flat_map(|e| e.actions.values()))
+        .any(|action| *action == EventAction::Notify);
+    if notifies && notifier.is_none() {
+        return Err(ConfigError::Invalid(
+            "the notify action needs a notification target such as --ntfy-url".to_owned(),
+        ));
+    }
+
+    let shared = Arc::new(Shared {
+        runner,
+        policies: config.containers,
+        retry: Retry {
+            retries: opt.retries,
+            delay: opt.retry_delay,
+            max_delay: opt.retry_max_delay,
+            seed: None,
+        },
+        cooldown: opt.cooldown,
+        image_cooldown: opt.per_image_cooldown.map(ImageCooldown::new),
+        fail_on_no_update: opt.fail_on_no_update,
+        result_file: opt.result_file.map(|path| (path, opt.result_file_mode)),
+        response_secret: opt.response_secret,
+        max_response_containers: opt.max_response_containers,
+        required_fields: opt.required_fields,
+        required_user_agent: opt.require_user_agent,
+        image_details: opt.image_details,
+        verify_health: opt.verify_health.then_some(opt.verify_health_timeout),
+        host: opt
+            .host_info
+            .then(|| Arc::new(Host::read(opt.host_info_machine_id))),
+        report_skipped: opt.report_skipped,
+        passthrough: opt.passthrough,
+        pretty: opt.pretty,
+        #[cfg(feature = "journal")]
+        journal: opt.journal,
+        notifier,
+        update_pinger: opt.heartbeat_update_url.map(Pinger::new),
+        registry_probe: RegistryProbe::new(&opt.probe_registry),
+        breaker: opt
+            .breaker_threshold
+            .map(|threshold| Breaker::new(threshold, opt.breaker_cooldown)),
+        skip_status: opt.skip_status,
+        max_body_bytes: opt.max_body_bytes,
+        body_buffers: BufferPool::new(POOLED_BUFFERS, POOLED_BUFFER_BYTES),
+        changed_status: opt.changed_status,
+        unchanged_status: opt.unchanged_status,
+        proxies: TrustedProxies(opt.trusted_proxies.clone()),
+        update_permits: Semaphore::new(opt.max_concurrent_updates.min(Semaphore::MAX_PERMITS)),
+        coalesce_window: opt.coalesce_window,
+        max_queue: opt.max_inflight_queue,
+        window: opt.maintenance_window,
+        waiting: AtomicUsize::new(0),
+        running: AtomicUsize::new(0),
+        metrics: Metrics::default(),
+        deferred: AtomicUsize::new(0),
+        draining: AtomicBool::new(false),
+        last_success: Mutex::new(None),
+        last_webhook: Mutex::new(Instant::now()),
+        last_update: Mutex::new(None),
+        started: Instant::now(),
+        stale_after: opt.status_stale_after,
+        stale_status: opt.status_stale_code,
+    });
+
+    log_auth(&route(&hook_path), opt.command.as_ref());
+
+    // every keyring is reloaded together, whichever endpoint it belongs to
+    let mut keyrings = vec![];
+    // every endpoint keeps its own keys
+    let retention = opt.idempotency_retention;
+    let idempotency_cache = || (!retention.is_zero()).then(|| IdempotencyCache::new(retention));
+
+    let hook_keyring = keyring(opt.command.as_ref())?;
+    keyrings.extend(hook_keyring.clone());
+    let tokens = opt
+        .command
+        .iter()
+        .chain(config.endpoints.iter().flat_map(|e| e.auth.as_ref()))
+        .filter_map(bearer_token)
+        .map(token_digest)
+        .collect();
+    let mut router = Router::new().route(
+        &hook_path,
+        post(handler)
+            .layer(middleware::from_fn_with_state(
+                shared.clone(),
+                count_webhooks,
+            ))
+            .get(alive)
+            .with_state(Arc::new(Endpoint {
+                app_key: app_key(opt.command.as_ref())?,
+                keyring: hook_keyring,
+                signature_header: signature_header(opt.command.as_ref())?,
+                token: Token(opt.command),
+                args: vec![],
+                actions: config.actions.clone(),
+                deferred: AtomicBool::new(false),
+                replies: idempotency_cache(),
+                batch: Mutex::default(),
+                shared: shared.clone(),
+            })),
+    );
+
+    for endpoint in config.endpoints {
+        let path = format!("{}/{}", hook_path, endpoint.name);
+        log_auth(&route(&path), endpoint.auth.as_ref());
+        let endpoint_keyring = keyring(endpoint.auth.as_ref())?;
+        keyrings.extend(endpoint_keyring.clone());
+        router = router.route(
+            &path,
+            post(handler)
+                .layer(middleware::from_fn_with_state(
+                    shared.clone(),
+                    count_webhooks,
+                ))
+                .get(alive)
+                .with_state(Arc::new(Endpoint {
+                    app_key: app_key(endpoint.auth.as_ref())?,
+                    keyring: endpoint_keyring,
+                    signature_header: signature_header(endpoint.auth.as_ref())?,
+                    token: Token(endpoint.auth),
+                    args: endpoint.args,
+                    actions: config
+                        .actions
+                        .iter()
+                        .map(|(event, action)| (event.clone(), *action))
+                        .chain(endpoint.actions)
+                        .collect(),
+                    deferred: AtomicBool::new(false),
+                    replies: idempotency_cache(),
+                    batch: Mutex::default(),
+                    shared: shared.clone(),
+                })),
+        );
+    }
+
+    if let Some(token) = opt.admin_token {
+        tracing::info!("{}: accepting authorization header", route("/admin"));
+        let admin = Arc::new(Admin {
+            token,
+            shared: shared.clone(),
+        });
+        router = router
+            .route("/admin/drain", post(drain).with_state(admin.clone()))
+            .route("/admin/resume", post(resume).with_state(admin));
+    }
+
+    // a proxy on this host would make every client look local
+    if (opt.dashboard || opt.status || opt.metrics) && opt.trusted_proxies.is_empty() {
+        tracing::warn!(
+            "the dashboard, /status and /metrics trust every client on this machine; if a \
+             reverse proxy here forwards to the hook, pass --trusted-proxy for it or they are \
+             open to everyone"
+        );
+    }
+    if opt.dashboard {
+        tracing::info!("{}: serving the dashboard to local clients", route("/"));
+        router = router.route("/", get(dashboard).with_state(shared.clone()));
+    }
+    if opt.status {
+        tracing::info!(
+            "{}: serving the last update to local clients",
+            route("/status")
+        );
+        router = router.route("/status", get(status).with_state(shared.clone()));
+    }
+
+    // build our application with a route
+    let app = match opt.no_rate_limit {
+        true => {
+            tracing::warn!("rate limiting is off");
+            router
+        }
+        false => rate_limit(
+            router,
+            opt.rate_limit_period,
+            opt.rate_limit_burst,
+            UserToken {
+                tokens,
+                proxies: TrustedProxies(opt.trusted_proxies),
+            },
+        )?,
+    };
+    let app = app.route(
+        "/health",
+        get(health).with_state(Arc::new(Health {
+            probe: opt.probe_systemd,
+            shared: shared.clone(),
+        })),
+    );
+    // after the rate limit, so that scrapes don't count against it
+    let app = match opt.metrics {
+        true => {
+            tracing::info!("{}: serving metrics to local clients", route("/metrics"));
+            for range in &opt.metrics_allow {
+                tracing::info!("{}: serving metrics to {}", route("/metrics"), range);
+            }
+            app.route(
+                "/metrics",
+                get(metrics).with_state(Arc::new(Scrapers {
+                    allowed: opt.metrics_allow,
+                    shared: shared.clone(),
+                })),
+            )
+        }
+        false => app,
+    };
+    let app = match base_path.is_empty() {
+        true => app,
+        false => Router::new().nest(&base_path, app),
+    };
+
+    let router = match opt.log_requests {
+        true => app.layer(middleware::from_fn(access_log)),
+        false => app,
+    };
+    let router = match shared.host.clone() {
+        Some(host) => router.layer(middleware::from_fn_with_state(host, host_span)),
+        None => router,
+    };
+
+    Ok(App {
+        router,
+        status: Status { shared, keyrings },
+    })
+}
+
+/// Limits how often each client can call the routes so far. The config
+/// must live as long as the layer, so it is leaked once per app.
+fn rate_limit(
+    router: Router,
+    period: Duration,
+    burst: u32,
+    key_extractor: UserToken,
+) -> Result<Router, ConfigError> {
+    let governor_conf = GovernorConfigBuilder::default()
+        .period(period)
+        .burst_size(burst)
+        .key_extractor(key_extractor.clone())
+        .use_headers()
+        .finish()
+        .ok_or_else(|| {
+            ConfigError::Invalid("--rate-limit-period must be more than zero".to_owned())
+        })?;
+
+    Ok(router.layer(
+        ServiceBuilder::new()
+            // this middleware goes above `GovernorLayer` because it will receive
+            // errors returned by `GovernorLayer`
+            .layer(HandleErrorLayer::new(
+                move |ConnectInfo(peer): ConnectInfo<SocketAddr>,
+                      RawQuery(query): RawQuery,
+                      headers: HeaderMap,
+                      e: BoxError| {
+                    let key = key_extractor.key(peer.ip(), &headers, query.as_deref());
+                    async move {
+                        if let Some(GovernorError::TooManyRequests { wait_time, .. }) =
+                            e.downcast_ref::<GovernorError>()
+                        {
+                            tracing::warn!(
+                                "rate limited request from {} for {}s",
+                                key_fingerprint(&key),
+                                wait_time
+                            );
+                        }
+                        display_error(e)
+                    }
+                },
+            ))
+            .layer(GovernorLayer {
+                config: Box::leak(Box::new(governor_conf)),
+            }),
+    ))
+}
+
+/// Normalizes a route to a single leading slash and no empty segments, so
+/// `podman/` and `//hook/` become `/podman` and `/hook`.
+fn normalize_path(path: &str) -> Result<String, ConfigError> {
+    if path.contains(|c: char| c.is_whitespace() || c == '?' || c == '#') {
+        return Err(ConfigError::Invalid(format!(
+            "route {:?} can't contain whitespace, ? or #",
+            path
+        )));
+    }
+    let segments: Vec<_> = path.split('/').filter(|s| !s.is_empty()).collect();
+    Ok(format!("/{}", segments.join("/")))
+}
+
+/// Runs the request in a span naming the host, so every log line of a fleet
+/// says where it came from.
+async fn host_span<B>(
+    State(host): State<Arc<Host>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let span = tracing::info_span!(
+        "request",
+        hostname = %host.hostname,
+        machine_id = host.machine_id.as_deref()
+    );
+    next.run(request).instrument(span).await
+}
+
+/// Logs every request once it has been answered. Only the path is logged
+/// (not the query) and credentials are reduced to whether they were accepted.
+async fn access_log<B>(request: Request<B>, next: Next<B>) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let credentials = request.headers().contains_key(AUTHORIZATION)
+        || request.headers().contains_key(&GITHUB_SIGNATURE_256);
+    let start = Instant::now();
+
+    let response = next.run(request).await;
+
+    let status = response.status();
+    let auth = match (credentials, status) {
+        (_, StatusCode::UNAUTHORIZED) => "rejected",
+        (true, _) => "accepted",
+        (false, _) => "none",
+    };
+    tracing::info!(
+        target: "access",
+        method = %method,
+        path,
+        status = status.as_u16(),
+        auth,
+        duration_ms = start.elapsed().as_millis() as u64,
+        "request"
+    );
+    response
+}
+
+/// Counts a request in one of the `waiting` or `running` gauges until dropped.
+struct Counted<'a>(&'a AtomicUsize);
+
+impl Drop for Counted<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Waits for a turn to run an update, or gives up immediately if the queue
+/// of waiting requests is already full.
+async fn acquire_update_permit(shared: &Shared) -> Option<SemaphorePermit<'_>> {
+    if let Ok(permit) = shared.update_permits.try_acquire() {
+        return Some(permit);
+    }
+
+    let waiting = shared.waiting.fetch_add(1, Ordering::SeqCst);
+    let _waiting = Counted(&shared.waiting);
+    if shared.max_queue.is_some_and(|max| waiting >= max) {
+        return None;
+    }
+
+    shared.update_permits.acquire().await.ok()
+}
+
+fn parse_env(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("{:?} is not in the form KEY=VALUE", s)),
+    }
+}
+
+fn parse_cidr(s: &str) -> Result<IpNet, String> {
+    s.parse()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{:?} is not an ip address or CIDR range", s))
+}
+
+fn parse_success_status(s: &str) -> Result<StatusCode, String> {
+    match s.parse::<u16>().map(StatusCode::from_u16) {
+        Ok(Ok(status)) if status.is_success() => Ok(status),
+        _ => Err(format!("{} is not a 2xx status code", s)),
+    }
+}
+
+fn parse_status(s: &str) -> Result<StatusCode, String> {
+    match s.parse::<u16>().map(StatusCode::from_u16) {
+        Ok(Ok(status)) => Ok(status),
+        _ => Err(format!("{} is not a status code", s)),
+    }
+}
+
+fn app_key(command: Option<&TokenCommand>) -> Result<Option<DecodingKey>, ConfigError> {
+    match command {
+        Some(TokenCommand::GithubApp { public_key, .. }) => {
+            github_app::load_key(public_key).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+fn keyring(command: Option<&TokenCommand>) -> Result<Option<Arc<Keyring>>, ConfigError> {
+    match command {
+        Some(TokenCommand::Github {
+            secret_keyring: Some(path),
+            ..
+        }) => Keyring::load(path).map(|k| Some(Arc::new(k))),
+        _ => Ok(None),
+    }
+}
+
+fn signature_header(command: Option<&TokenCommand>) -> Result<HeaderName, ConfigError> {
+    match command {
+        Some(TokenCommand::Github {
+            signature_header: Some(name),
+            ..
+        }) => HeaderName::from_str(name)
+            .map_err(|_| ConfigError::Invalid(format!("{:?} is not a valid header name", name))),
+        _ => Ok(GITHUB_SIGNATURE_256.clone()),
+    }
+}
+
+fn log_auth(path: &str, command: Option<&TokenCommand>) {
+    match command {
+        Some(TokenCommand::Token { .. }) => {
+            tracing::info!("{}: accepting authorization header", path);
+        }
+        Some(TokenCommand::Github {
+            events,
+            repos,
+            secondary_secret_expires,
+            ..
+        }) => {
+            tracing::info!("{}: accepting github events: {:?}", path, events);
+            if !repos.is_empty() {
+                tracing::info!("{}: accepting github repositories: {:?}", path, repos);
+            }
+            match secondary_secret_expires {
+                Some(expires) if expires.0 <= SystemTime::now() => {
+                    tracing::warn!("{}: the secondary secret expired at {}", path, expires);
+                }
+                Some(expires) => {
+                    tracing::info!("{}: accepting the secondary secret until {}", path, expires);
+                }
+                None => {}
+            }
+        }
+        Some(TokenCommand::GithubApp { issuer, .. }) => {
+            tracing::info!("{}: accepting github app tokens issued by {}", path, issuer);
+        }
+        Some(TokenCommand::Registry { images, .. }) if images.is_empty() => {
+            tracing::info!("{}: accepting registry pushes of any image", path);
+        }
+        Some(TokenCommand::Registry { images, .. }) => {
+            tracing::info!("{}: accepting registry pushes of {:?}", path, images);
+        }
+        None => tracing::warn!(
+            "{}: accepting unauthenticated requests, anyone who can reach it can run updates",
+            path
+        ),
+    }
+}
+
+async fn handler(
+    State(endpoint): State<Arc<Endpoint>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    github_event: Option<TypedHeader<GithubEvent>>,
+    RawQuery(query): RawQuery,
+    mut stream: BodyStream,
+) -> Result<Response, HookError> {
+    let Token(token) = &endpoint.token;
+    let required_fields = &endpoint.shared.required_fields;
+    let inspect_payload = !required_fields.is_empty() || endpoint.shared.image_cooldown.is_some();
+    *endpoint.shared.last_webhook.lock().unwrap() = Instant::now();
+    let client = endpoint.shared.proxies.client_ip(peer.ip(), &headers);
+
+    // a coarse filter for scanners, checked before the more expensive auth
+    if let Some(required) = &endpoint.shared.required_user_agent {
+        let user_agent = headers.typed_get::<UserAgent>();
+        if !user_agent
+            .as_ref()
+            .is_some_and(|ua| ua.as_str().contains(required.as_str()))
+        {
+            tracing::debug!("user agent {:?} not allowed, rejecting", user_agent);
+            return Err(HookError::new(
+                StatusCode::FORBIDDEN,
+                "user agent not allowed",
+            ));
+        }
+    }
+
+    // the body is checked as it streams in too, in case there is no length
+    let max_body = endpoint.shared.max_body_bytes;
+    let length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+    if length.is_some_and(|length| length > max_body as u64) {
+        tracing::debug!("body of {:?} bytes is too large, rejecting", length);
+        return Err(body_too_large());
+    }
+    let mut received = 0;
+    // the body is only buffered when a payload filter needs to inspect it,
+    // growing as chunks arrive rather than by the length a sender claims
+    let mut body = endpoint.shared.body_buffers.take();
+
+    let github_signature = headers
+        .get(&endpoint.signature_header)
+        .and_then(|value| value.to_str().ok());
+    match (token, auth, github_signature, github_event) {
+        (Some(TokenCommand::Token { bearer: t1 }), Some(TypedHeader(t2)), None, None)
+            if tokens_match(t1, t2.token()) =>
+        {
+            audit(client, "token", Ok(()));
+        }
+        (Some(TokenCommand::Token { .. }), auth, _, _) => {
+            tracing::debug!("token mismatch");
+            let reason = match auth {
+                Some(_) => "token mismatch",
+                None => "missing token",
+            };
+            audit(client, "token", Err(reason));
+            return Err(HookError::unauthorized(reason));
+        }
+        (Some(TokenCommand::GithubApp { issuer, .. }), auth, _, _) => {
+            let Some(TypedHeader(auth)) = auth else {
+                tracing::debug!("missing github app token");
+                audit(client, "github-app", Err("missing token"));
+                return Err(HookError::unauthorized("missing token"));
+            };
+            let key = endpoint
+                .app_key
+                .as_ref()
+                .expect("github app endpoints have a key");
+            if let Err(e) = github_app::verify(key, issuer, auth.token()) {
+                tracing::debug!("invalid github app token: {}", e);
+                audit(client, "github-app", Err("invalid token"));
+                return Err(HookError::unauthorized("invalid token"));
+            }
+            audit(client, "github-app", Ok(()));
+        }
+        (Some(TokenCommand::Registry { secret, images }), auth, _, _) => {
+            // docker hub can't send headers, so the token may be in the url
+            let query: TokenQuery =
+                serde_urlencoded::from_str(query.as_deref().unwrap_or_default())
+                    .unwrap_or_default();
+            let token = auth
+                .as_ref()
+                .map(|TypedHeader(auth)| auth.token())
+                .or(query.token.as_deref());
+            if !token.is_some_and(|token| tokens_match(secret, token)) {
+                tracing::debug!("registry token mismatch");
+                let reason = match token {
+                    Some(_) => "token mismatch",
+                    None => "missing token",
+                };
+                audit(client, "registry", Err(reason));
+                return Err(HookError::unauthorized(reason));
+            }
+            audit(client, "registry", Ok(()));
+
+            while let Some(b) = next_chunk(&mut stream, &mut received, max_body).await? {
+                body.extend_from_slice(&b);
+            }
+            let payload = json_payload(&headers, &body)?;
+            let payload: serde_json::Value = serde_json::from_slice(&payload).map_err(|e| {
+                tracing::debug!("failed to parse registry payload: {}", e);
+                HookError::bad_request("invalid json payload")
+            })?;
+            match push::pushed_image(&payload) {
+                None => {
+                    tracing::debug!("no pushed image in the payload, ignoring");
+                    return Ok(skipped(endpoint.shared.skip_status));
+                }
+                Some(pushed)
+                    if !images.is_empty() && !images.iter().any(|i| push::matches(i, &pushed)) =>
+                {
+                    tracing::debug!("{} isn't one of the endpoint's images, ignoring", pushed);
+                    return Ok(skipped(endpoint.shared.skip_status));
+                }
+                Some(pushed) => tracing::debug!("{} was pushed", pushed),
+            }
+        }
+        (
+            Some(TokenCommand::Github {
+                secret,
+                events,
+                additional_secrets,
+                repos,
+                secondary_secret,
+                secondary_secret_expires,
+                github_hook_id: expected_hook_id,
+                ..
+            }),
+            None,
+            Some(signature),
+            event,
+        ) => {
+            // the secondary secret is tried last, and only until it expires
+            let secondary = match (secondary_secret, secondary_secret_expires) {
+                (Some(secret), Some(expires)) if SystemTime::now() < expires.0 => {
+                    Some((secret, expires))
+                }
+                _ => None,
+            };
+            let secondary_index = 1 + additional_secrets.len();
+            let keyring_index = secondary_index + usize::from(secondary.is_some());
+            let keyring = endpoint
+                .keyring
+                .as_ref()
+                .map(|k| k.keys())
+                .unwrap_or_default();
+            // github signs the body exactly as sent, so a form delivery is
+            // verified as a whole before `json_payload` unwraps it
+            let mut verifier = Verifier::new(
+                std::iter::once(secret)
+                    .chain(additional_secrets)
+                    .chain(secondary.map(|(secret, _)| secret))
+                    .chain(keyring.iter()),
+            );
+            // a truncated body would only show up as a signature mismatch
+            while let Some(b) = next_chunk(&mut stream, &mut received, max_body).await? {
+                verifier.update(&b);
+                if !repos.is_empty() || inspect_payload {
+                    body.extend_from_slice(&b);
+                }
+            }
+
+            let Some((_, signature_exp)) = signature.split_once('=') else {
+                audit(client, "github", Err("malformed signature"));
+                return Err(HookError::bad_request("malformed signature"));
+            };
+            let matched = verifier.verify(signature_exp);
+
+            match (matched, secondary) {
+                (Some(i), Some((_, expires))) if i == secondary_index => tracing::info!(
+                    "github signature matched the secondary secret, which expires at {}",
+                    expires
+                ),
+                (Some(i), _) if i >= keyring_index => tracing::debug!(
+                    "github signature matched key {} of the keyring",
+                    i - keyring_index
+                ),
+                (Some(i), _) => tracing::debug!("github signature matched secret {}", i),
+                (None, _) => {
+                    tracing::debug!("github signature mismatch");
+                    audit(client, "github", Err("signature mismatch"));
+                    return Err(HookError::unauthorized("signature mismatch"));
+                }
+            }
+            audit(client, "github", Ok(()));
+
+            match (&events[..], event) {
+                ([], _) => {}
+                (_, None) => {
+                    tracing::debug!("missing github event header");
+                    return Err(HookError::bad_request("missing github event header"));
+                }
+                (e, Some(TypedHeader(GithubEvent(event)))) if !e.contains(&event) => {
+                    tracing::debug!("github event mismatch, ignoring");
+                    return Ok(skipped(endpoint.shared.skip_status));
+                }
+                _ => {}
+            }
+
+            if let Some(expected) = expected_hook_id {
+                let hook_id = headers
+                    .typed_get::<GithubHookId>()
+                    .map(|GithubHookId(id)| id);
+                if hook_id.as_ref() != Some(expected) {
+                    tracing::debug!("github hook id {:?} not allowed, ignoring", hook_id);
+                    return Ok(skipped(endpoint.shared.skip_status));
+                }
+            }
+
+            if !repos.is_empty() {
+                let payload = json_payload(&headers, &body)?;
+                let payload: GithubPayload = serde_json::from_slice(&payload).map_err(|e| {
+                    tracing::debug!("failed to parse github payload: {}", e);
+                    HookError::bad_request("invalid json payload")
+                })?;
+
+                match payload.repository {
+                    Some(Repository { full_name })
+                        if repos.iter().any(|r| r.eq_ignore_ascii_case(&full_name)) => {}
+                    repository => {
+                        tracing::debug!(
+                            "github repository {:?} not allowed, ignoring",
+                            repository.map(|r| r.full_name)
+                        );
+                        return Ok(skipped(endpoint.shared.skip_status));
+                    }
+                }
+            }
+        }
+        (Some(TokenCommand::Github { .. }), _, None, _) => {
+            tracing::debug!("missing github signature header");
+            audit(client, "github", Err("missing signature"));
+            return Err(HookError::bad_request("missing signature"));
+        }
+        (Some(TokenCommand::Github { .. }), Some(_), Some(_), _) => {
+            tracing::debug!("github endpoints don't accept bearer tokens");
+            audit(client, "github", Err("unexpected bearer token"));
+            return Err(HookError::unauthorized("unexpected bearer token"));
+        }
+        (None, _, _, _) => audit(client, "none", Ok(())),
+    }
+
+    if inspect_payload {
+        // a github body has already been read, this picks up any other kind
+        while let Some(b) = next_chunk(&mut stream, &mut received, max_body).await? {
+            body.extend_from_slice(&b);
+        }
+    }
+    // the rest of a body nothing looked at, so the limit holds whatever the
+    // auth and however the body is sent
+    while next_chunk(&mut stream, &mut received, max_body)
+        .await?
+        .is_some()
+    {}
+    if !required_fields.is_empty() {
+        let payload = json_payload(&headers, &body)?;
+        let payload: serde_json::Value = serde_json::from_slice(&payload).map_err(|e| {
+            tracing::debug!("failed to parse payload: {}", e);
+            HookError::bad_request("invalid json payload")
+        })?;
+        if let Some(field) = required_fields
+            .iter()
+            .find(|field| payload.pointer(&json_pointer(field)).is_none())
+        {
+            tracing::debug!("payload is missing {:?}, rejecting", field);
+            return Err(HookError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("payload is missing {}", field),
+            ));
+        }
+    }
+
+    // a payload without an image falls back to the global checks
+    let image = match &endpoint.shared.image_cooldown {
+        Some(cooldown) => {
+            let image = json_payload(&headers, &body)
+                .ok()
+                .and_then(|payload| serde_json::from_slice(&payload).ok())
+                .and_then(|payload| push::pushed_image(&payload))
+                .map(|image| image.to_string());
+            let pulled = image
+                .as_deref()
+                .and_then(|i| Some((i, cooldown.pulled_within(i)?)));
+            if let Some((image, ago)) = pulled {
+                tracing::debug!("{} was updated {:?} ago, ignoring", image, ago);
+                return Ok(skipped(endpoint.shared.skip_status));
+            }
+            image
+        }
+        None => None,
+    };
+
+    // hand the buffer back before the (potentially slow) update runs
+    drop(body);
+
+    // checked up front so a client that can't read the result doesn't update
+    let accept: Vec<_> = headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    let accept = (!accept.is_empty()).then(|| accept.join(","));
+    // podman's own output is only ever JSON
+    let supported: &[_] = match endpoint.shared.passthrough {
+        true => &[Format::Json],
+        false => &[Format::Json, Format::Text, Format::Metrics],
+    };
+    let Some(format) = Format::negotiate(accept.as_deref(), supported) else {
+        tracing::debug!("can't respond with any of {:?}", accept);
+        return Err(HookError::new(
+            StatusCode::NOT_ACCEPTABLE,
+            "no supported response format",
+        ));
+    };
+
+    let event = headers.typed_get::<GithubEvent>().map(|GithubEvent(e)| e);
+    match endpoint.action(event.as_deref()) {
+        EventAction::AutoUpdate => {}
+        EventAction::Notify => {
+            let event = event.as_deref().unwrap_or("webhook");
+            tracing::info!("{} event is mapped to notify, not updating", event);
+            if let Some(notifier) = &endpoint.shared.notifier {
+                notifier.notify_event(event);
+            }
+            return Ok((StatusCode::ACCEPTED, counts(0, 0)).into_response());
+        }
+        EventAction::Skip => {
+            tracing::debug!("{:?} event is mapped to skip, ignoring", event);
+            return Ok(skipped(endpoint.shared.skip_status));
+        }
+    }
+
+    // a repeat is answered even while draining, since it doesn't update
+    let claim = match (&endpoint.replies, headers.get(IDEMPOTENCY_KEY)) {
+        (Some(replies), Some(key)) => {
+            let Some(key) = key.to_str().ok().filter(|key| key.len() <= 255) else {
+                tracing::debug!("malformed idempotency key");
+                return Err(HookError::bad_request("malformed idempotency key"));
+            };
+            match replies.claim(key) {
+                Claimed::New(claim) => Some(claim),
+                Claimed::Replay(response) => {
+                    tracing::info!("replaying the response for idempotency key {:?}", key);
+                    return Ok(response);
+                }
+                Claimed::InFlight => {
+                    tracing::debug!("idempotency key {:?} is still in flight", key);
+                    return Err(HookError::new(
+                        StatusCode::CONFLICT,
+                        "idempotency key is still in flight",
+                    ));
+                }
+            }
+        }
+        _ => None,
+    };
+
+    if endpoint.shared.draining.load(Ordering::SeqCst) {
+        tracing::info!("draining, rejecting update");
+        return Ok(unavailable("draining", RETRY_AFTER_SECS));
+    }
+
+    if let Some(wait) = endpoint.shared.window.and_then(|w| w.until_open()) {
+        return Ok(defer(&endpoint, wait));
+    }
+
+    let reply = update_reply(&endpoint, event.as_deref(), image, format).await;
+    if let Some(claim) = claim {
+        claim.finish(reply.as_ref().ok());
+    }
+    Ok(match reply {
+        Ok(reply) => reply.into_response(),
+        Err(response) => response,
+    })
+}
+
+/// Runs the update for a webhook and builds the response for its result.
+async fn update_reply(
+    endpoint: &Arc<Endpoint>,
+    event: Option<&str>,
+    image: Option<String>,
+    format: Format,
+) -> Result<Reply, Response> {
+    let update = coalesced_update(endpoint, event).await?;
+    if let (Some(cooldown), Some(image)) = (&endpoint.shared.image_cooldown, image) {
+        cooldown.record(image);
+    }
+    if let Some(raw) = update.raw {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        if let Some(secret) = &endpoint.shared.response_secret {
+            headers.typed_insert(sign_response(secret, &raw));
+        }
+        return Ok(Reply {
+            status: StatusCode::OK,
+            headers,
+            body: raw.into(),
+            skipped: false,
+        });
+    }
+    let response = &update.containers;
+
+    let changed = response.iter().any(AutoUpdateReponse::changed);
+    if endpoint.shared.fail_on_no_update && !changed {
+        tracing::info!("no containers were updated");
+        return Err(
+            HookError::new(StatusCode::CONFLICT, "no containers were updated").into_response(),
+        );
+    }
+
+    let status = if response
+        .iter()
+        .any(|c| c.health == Some(podman::Health::Unhealthy))
+    {
+        StatusCode::MULTI_STATUS
+    } else if changed {
+        endpoint.shared.changed_status
+    } else {
+        endpoint.shared.unchanged_status
+    };
+
+    let body = match format {
+        // the counts headers still say what happened
+        _ if status == StatusCode::NO_CONTENT => Vec::new(),
+        Format::Json => endpoint.shared.response_body(&update).map_err(|e| {
+            tracing::error!("failed to serialize response: {}", e);
+            HookError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to serialize response",
+            )
+            .into_response()
+        })?,
+        Format::Text => output::text(response).into_bytes(),
+        Format::Metrics => output::metrics(response).into_bytes(),
+    };
+
+    let mut headers = counts(
+        response
+            .iter()
+            .filter(|r| r.updated == Updated::True)
+            .count(),
+        response.iter().filter(|r| r.changed()).count(),
+    );
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    if let Some(secret) = &endpoint.shared.response_secret {
+        headers.typed_insert(sign_response(secret, &body));
+    }
+
+    Ok(Reply {
+        status,
+        headers,
+        body: body.into(),
+        skipped: false,
+    })
+}
+
+/// The next chunk of a webhook body. Once more than `max` bytes have been
+/// `received` it's rejected, so an unauthenticated sender can't make the
+/// hook hash an endless body.
+async fn next_chunk(
+    stream: &mut BodyStream,
+    received: &mut usize,
+    max: usize,
+) -> Result<Option<Bytes>, HookError> {
+    let Some(chunk) = stream.next().await else {
+        return Ok(None);
+    };
+    // e.g. because the client disconnected
+    let chunk = chunk.map_err(|e| {
+        tracing::warn!("failed to read the request body: {}", e);
+        HookError::bad_request("incomplete body")
+    })?;
+    *received += chunk.len();
+    if *received > max {
+        tracing::debug!("body is larger than {} bytes, rejecting", max);
+        return Err(body_too_large());
+    }
+    Ok(Some(chunk))
+}
+
+fn body_too_large() -> HookError {
+    HookError::new(StatusCode::PAYLOAD_TOO_LARGE, "body too large")
+}
+
+/// The JSON document in a webhook body. GitHub sends it as is, or as the
+/// `payload` field of a form when the webhook's content type is set to
+/// `application/x-www-form-urlencoded`. Anything else is rejected with a 415.
+fn json_payload<'a>(headers: &HeaderMap, body: &'a [u8]) -> Result<Cow<'a, [u8]>, HookError> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        });
+
+    match content_type.as_deref() {
+        None | Some("application/json") => Ok(Cow::Borrowed(body)),
+        Some(json) if json.ends_with("+json") => Ok(Cow::Borrowed(body)),
+        Some("application/x-www-form-urlencoded") => {
+            let form: FormPayload = serde_urlencoded::from_bytes(body).map_err(|e| {
+                tracing::debug!("failed to parse form payload: {}", e);
+                HookError::bad_request("invalid form payload")
+            })?;
+            Ok(Cow::Owned(form.payload.into_bytes()))
+        }
+        Some(other) => {
+            tracing::debug!("unsupported content type {:?}, expected json", other);
+            Err(HookError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "expected a json payload",
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FormPayload {
+    payload: String,
+}
+
+/// The query of a registry webhook's url.
+#[derive(Debug, Default, Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+fn add_image_details(response: &mut [AutoUpdateReponse]) {
+    for container in response {
+        container.reference = Some(ImageReference::parse(&container.image));
+    }
+}
+
+/// Serializes a response body. Fields are always written in declaration
+/// order, so the same result gives the same bytes.
+fn to_json(value: &impl Serialize, pretty: bool) -> serde_json::Result<Vec<u8>> {
+    match pretty {
+        true => serde_json::to_vec_pretty(value),
+        false => serde_json::to_vec(value),
+    }
+}
+
+/// Turns a dotted path like `repository.name` (or `commits.0.id` for arrays)
+/// into a JSON pointer.
+fn json_pointer(path: &str) -> String {
+    path.split('.')
+        .map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+const UPDATED_COUNT: HeaderName = HeaderName::from_static("x-updated-count");
+const CHANGED_COUNT: HeaderName = HeaderName::from_static("x-changed-count");
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Headers with the number of containers that were restarted and the number
+/// that had a newer image (including pending ones), for cheap scraping.
+fn counts(updated: usize, changed: usize) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(UPDATED_COUNT, updated.into());
+    headers.insert(CHANGED_COUNT, changed.into());
+    headers
+}
+
+/// The response for an update that can't run right now but may be retried.
+fn unavailable(message: &'static str, retry_after_secs: u64) -> Response {
+    (
+        [(RETRY_AFTER, retry_after_secs.to_string())],
+        HookError::new(StatusCode::SERVICE_UNAVAILABLE, message),
+    )
+        .into_response()
+}
+
+fn update_failed() -> Response {
+    HookError::new(StatusCode::INTERNAL_SERVER_ERROR, "update failed").into_response()
+}
+
+/// Records how a webhook was authenticated under the `audit` target, so it
+/// can be kept apart from the rest of the logs. Credentials are never logged.
+fn audit(client: IpAddr, mode: &'static str, result: Result<(), &'static str>) {
+    match result {
+        Ok(()) => {
+            tracing::info!(target: "audit", client = %client, mode, result = "success", "webhook authentication")
+        }
+        Err(reason) => {
+            tracing::info!(target: "audit", client = %client, mode, result = "failure", reason, "webhook authentication")
+        }
+    }
+}
+
+/// The result of a coalesced update, shared by every webhook in the batch.
+type Batched = Arc<Result<Update, Reply>>;
+
+/// Runs the update, or with `--coalesce-window` joins the batch of webhooks
+/// that is about to update. The first webhook of a batch schedules its
+/// update for the end of the window, and the ones arriving until then get
+/// the same result.
+async fn coalesced_update(
+    endpoint: &Arc<Endpoint>,
+    event: Option<&str>,
+) -> Result<Update, Response> {
+    let Some(window) = endpoint.shared.coalesce_window else {
+        return run_update(endpoint, event).await;
+    };
+
+    let mut result = {
+        let mut batch = endpoint.batch.lock().unwrap();
+        match &*batch {
+            Some(sender) => {
+                tracing::debug!("joining the update that is about to run");
+                sender.subscribe()
+            }
+            None => {
+                let (sender, receiver) = broadcast::channel(1);
+                *batch = Some(sender);
+                // spawned, so the batch still updates if the first client
+                // goes away
+                let endpoint = endpoint.clone();
+                let event = event.map(str::to_owned);
+                tokio::spawn(async move {
+                    tokio::time::sleep(window).await;
+                    // webhooks from now on start the next batch
+                    let Some(sender) = endpoint.batch.lock().unwrap().take() else {
+                        return;
+                    };
+                    let result = match run_update(&endpoint, event.as_deref()).await {
+                        Ok(update) => Ok(update),
+                        Err(response) => Err(Reply::buffer(response).await),
+                    };
+                    let _ = sender.send(Arc::new(result));
+                });
+                receiver
+            }
+        }
+    };
+
+    match result.recv().await.as_deref() {
+        Ok(Ok(update)) => Ok(update.clone()),
+        Ok(Err(reply)) => Err(reply.clone().into_response()),
+        Err(_) => Err(update_failed()),
+    }
+}
+
+/// Runs the update and records its outcome. When it can't run, the error is
+/// the response to send instead.
+async fn run_update(endpoint: &Endpoint, event: Option<&str>) -> Result<Update, Response> {
+    let Some(_permit) = acquire_update_permit(&endpoint.shared).await else {
+        tracing::warn!("too many updates waiting, rejecting");
+        return Err(unavailable("too many updates waiting", RETRY_AFTER_SECS));
+    };
+
+    if let Some(cooldown) = endpoint.shared.cooldown {
+        let last_success = *endpoint.shared.last_success.lock().unwrap();
+        if last_success.is_some_and(|t| t.elapsed() < cooldown) {
+            tracing::debug!("last update was less than {:?} ago, ignoring", cooldown);
+            return Err(skipped(endpoint.shared.skip_status));
+        }
+    }
+
+    if let Some(remaining) = endpoint
+        .shared
+        .breaker
+        .as_ref()
+        .and_then(Breaker::retry_after)
+    {
+        tracing::info!("circuit breaker is open, rejecting update");
+        // round up so clients don't come back just before it closes
+        return Err(unavailable(
+            "circuit breaker is open",
+            remaining.as_secs() + 1,
+        ));
+    }
+
+    if let Some(probe) = &endpoint.shared.registry_probe {
+        let unreachable = probe.unreachable().await;
+        if !unreachable.is_empty() {
+            let message = format!("unreachable registry: {}", unreachable.join(", "));
+            tracing::error!("{}, not updating", message);
+            return Err(HookError::new(StatusCode::BAD_GATEWAY, message).into_response());
+        }
+    }
+
+    tracing::info!(event, "running update");
+    endpoint.shared.running.fetch_add(1, Ordering::SeqCst);
+    let _running = Counted(&endpoint.shared.running);
+
+    let started = Instant::now();
+    let update = match &endpoint.shared.runner {
+        Runner::Cli(cli) if endpoint.shared.passthrough => cli
+            .auto_update_raw(&endpoint.args, endpoint.shared.retry)
+            .await
+            .map(|raw| Update {
+                raw: Some(raw),
+                ..Update::default()
+            }),
+        runner => {
+            runner
+                .update(
+                    &endpoint.args,
+                    &endpoint.shared.policies,
+                    endpoint.shared.retry,
+                )
+                .await
+        }
+    };
+    endpoint.shared.metrics.record_update(started.elapsed());
+    let mut update = match update {
+        Ok(update) => update,
+        Err(e) => {
+            tracing::error!("{}", e);
+            #[cfg(feature = "journal")]
+            if endpoint.shared.journal {
+                journal::record(event, Err(&e.to_string()));
+            }
+            if let Some(breaker) = &endpoint.shared.breaker {
+                breaker.record(false);
+            }
+            return Err(update_failed());
+        }
+    };
+
+    *endpoint.shared.last_success.lock().unwrap() = Some(Instant::now());
+    if let Some(breaker) = &endpoint.shared.breaker {
+        breaker.record(true);
+    }
+    if let Some(pinger) = &endpoint.shared.update_pinger {
+        pinger.ping();
+    }
+    if update.raw.is_some() {
+        // the output is passed on unread, so there is nothing to record
+        return Ok(update);
+    }
+
+    if endpoint.shared.image_details {
+        add_image_details(&mut update.containers);
+    }
+    if let (Runner::Cli(cli), Some(timeout)) =
+        (&endpoint.shared.runner, endpoint.shared.verify_health)
+    {
+        cli.verify_health(&mut update.containers, timeout).await;
+    }
+    endpoint.shared.metrics.record_updated(
+        update
+            .containers
+            .iter()
+            .filter(|c| c.updated == Updated::True)
+            .count(),
+    );
+    #[cfg(feature = "journal")]
+    if endpoint.shared.journal {
+        journal::record(event, Ok(&update.containers));
+    }
+    *endpoint.shared.last_update.lock().unwrap() = Some(LastUpdate {
+        at: SystemTime::now(),
+        containers: update.containers.clone(),
+    });
+    if let Some(notifier) = &endpoint.shared.notifier {
+        notifier.notify(&update.containers);
+    }
+
+    if let Some((path, mode)) = &endpoint.shared.result_file {
+        if let Err(e) = write_result(path, *mode, &update.containers).await {
+            tracing::error!("failed to write result file {}: {}", path.display(), e);
+        }
+    }
+
+    Ok(update)
+}
+
+/// Schedules an update for when the maintenance window opens. However many
+/// webhooks arrive before then, the update only runs once.
+fn defer(endpoint: &Arc<Endpoint>, wait: Duration) -> Response {
+    let scheduled = humantime::format_rfc3339_seconds(SystemTime::now() + wait).to_string();
+    if endpoint.deferred.swap(true, Ordering::SeqCst) {
+        tracing::debug!("an update is already deferred until {}", scheduled);
+    } else {
+        tracing::info!(
+            "outside the maintenance window, deferring the update until {}",
+            scheduled
+        );
+        let endpoint = endpoint.clone();
+        endpoint.shared.deferred.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(async move {
+            let _deferred = Counted(&endpoint.shared.deferred);
+            tokio::time::sleep(wait).await;
+            endpoint.deferred.store(false, Ordering::SeqCst);
+            if endpoint.shared.draining.load(Ordering::SeqCst) {
+                tracing::info!("draining, dropping the deferred update");
+                return;
+            }
+            // it stands in for every webhook that arrived meanwhile
+            match run_update(&endpoint, None).await {
+                Ok(update) => tracing::info!(
+                    "deferred update checked {} containers",
+                    update.containers.len()
+                ),
+                Err(response) => {
+                    tracing::warn!("deferred update didn't run ({})", response.status())
+                }
+            }
+        });
+    }
+
+    (
+        StatusCode::ACCEPTED,
+        counts(0, 0),
+        axum::Json(serde_json::json!({ "scheduled": scheduled })),
+    )
+        .into_response()
+}
+
+/// The response for a webhook that was intentionally ignored.
+fn skipped(status: StatusCode) -> Response {
+    let mut response = match status {
+        // a 204 can't have a body
+        StatusCode::NO_CONTENT => (status, counts(0, 0)).into_response(),
+        _ => (
+            status,
+            counts(0, 0),
+            axum::Json(serde_json::json!({ "status": "skipped" })),
+        )
+            .into_response(),
+    };
+    response.extensions_mut().insert(SkippedWebhook);
+    response
+}
+
+/// Signs the exact bytes of a response body, in the same `sha256=<hex>`
+/// format github uses for webhook deliveries.
+fn sign_response(secret: &str, body: &[u8]) -> HookSignature {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(body);
+    HookSignature(format!(
+        "sha256={}",
+        hex::encode(mac.finalize().into_bytes())
+    ))
+}
+
+/// The response body when it has more to say than the list of containers.
+#[derive(Serialize)]
+struct HookResponse<'a> {
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    host: Option<&'a Host>,
+    updated: &'a [AutoUpdateReponse],
+    /// Whether containers were left out of `updated`.
+    truncated: bool,
+    /// The number of containers in the update, including any left out.
+    total: usize,
+    /// The containers a filter left out, with `--report-skipped`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped: Option<&'a [Skipped]>,
+}
+
+#[derive(Serialize)]
+struct ResultRecord<'a> {
+    timestamp: String,
+    containers: &'a [AutoUpdateReponse],
+}
+
+async fn write_result(
+    path: &Path,
+    mode: ResultFileMode,
+    response: &[AutoUpdateReponse],
+) -> std::io::Result<()> {
+    let record = ResultRecord {
+        timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        containers: response,
+    };
+    let mut line = serde_json::to_vec(&record)?;
+    line.push(b'\n');
+
+    match mode {
+        ResultFileMode::Append => {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.write_all(&line).await
+        }
+        ResultFileMode::Overwrite => tokio::fs::write(path, line).await,
+    }
+}
+
+/// Stops accepting updates until `/admin/resume`. Updates that are already
+/// running or waiting for their turn are left to finish.
+async fn drain(
+    State(admin): State<Arc<Admin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+) -> StatusCode {
+    set_draining(&admin, auth, true)
+}
+
+async fn resume(
+    State(admin): State<Arc<Admin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+) -> StatusCode {
+    set_draining(&admin, auth, false)
+}
+
+fn set_draining(
+    admin: &Admin,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    draining: bool,
+) -> StatusCode {
+    let authorized = auth.is_some_and(|TypedHeader(auth)| tokens_match(&admin.token, auth.token()));
+    if !authorized {
+        tracing::debug!("admin token mismatch");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    match (
+        admin.shared.draining.swap(draining, Ordering::SeqCst),
+        draining,
+    ) {
+        (false, true) => tracing::info!("draining: no longer accepting updates"),
+        (true, false) => tracing::info!("resumed: accepting updates"),
+        _ => {}
+    }
+    StatusCode::OK
+}
+
+/// Lets uptime monitors check the hook url without triggering an update.
+async fn alive() -> StatusCode {
+    StatusCode::OK
+}
+
+/// The state for `/health`.
+struct Health {
+    probe: Option<SystemdScope>,
+    shared: Arc<Shared>,
+}
+
+const CIRCUIT_BREAKER: HeaderName = HeaderName::from_static("x-circuit-breaker");
+
+/// Reports the circuit breaker's state in a header. An open breaker doesn't
+/// fail the check, since restarting the hook won't bring a registry back.
+async fn health(State(health): State<Arc<Health>>) -> Response {
+    let mut headers = HeaderMap::new();
+    if let Some(breaker) = &health.shared.breaker {
+        headers.insert(
+            CIRCUIT_BREAKER,
+            HeaderValue::from_static(breaker.state().as_str()),
+        );
+    }
+
+    if let Some(scope) = health.probe {
+        if !systemd_reachable(scope).await {
+            return (StatusCode::SERVICE_UNAVAILABLE, headers).into_response();
+        }
+    }
+
+    (StatusCode::OK, headers).into_response()
+}
+
+/// Checks that `systemctl` can talk to the manager that `podman auto-update`
+/// restarts units through. A degraded system still counts as reachable.
+async fn systemd_reachable(scope: SystemdScope) -> bool {
+    let mut command = Command::new("systemctl");
+    if scope == SystemdScope::User {
+        command.arg("--user");
+    }
+
+    match command.arg("is-system-running").output().await {
+        Ok(output) => {
+            let state = String::from_utf8_lossy(&output.stdout);
+            match state.trim() {
+                "running" | "degraded" => true,
+                state => {
+                    tracing::warn!("systemd is not ready: {}", state);
+                    false
+                }
+            }
+        }
+        Err(e) => {
+            tracing::warn!("failed to run systemctl: {}", e);
+            false
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubPayload {
+    repository: Option<Repository>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Repository {
+    full_name: String,
+}
+
+/// Rate limits by bearer token, or by client address for requests without
+/// one. Only configured tokens get a limit of their own, since a sender
+/// could otherwise make up a new token (and get a new limit) each time.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct UserToken {
+    /// The sha256 of every bearer token a hook accepts.
+    tokens: HashSet<[u8; 32]>,
+    proxies: TrustedProxies,
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+enum RateLimitKey {
+    /// The sha256 of the token, so the limiter doesn't hold the secret.
+    Token([u8; 32]),
+    Ip(IpAddr),
+}
+
+impl UserToken {
+    fn key(&self, peer: IpAddr, headers: &HeaderMap, query: Option<&str>) -> RateLimitKey {
+        let digest = token_digest(&token_key(headers, query));
+        match self.tokens.contains(&digest) {
+            true => RateLimitKey::Token(digest),
+            false => RateLimitKey::Ip(self.proxies.client_ip(peer, headers)),
+        }
+    }
+}
+
+impl KeyExtractor for UserToken {
+    type Key = RateLimitKey;
+    type KeyExtractionError = GovernorError;
+
+    fn extract<B>(&self, req: &Request<B>) -> Result<Self::Key, Self::KeyExtractionError> {
+        let ConnectInfo(peer) = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .ok_or(GovernorError::UnableToExtractKey)?;
+        Ok(self.key(peer.ip(), req.headers(), req.uri().query()))
+    }
+
+    fn key_name(&self, key: &Self::Key) -> Option<String> {
+        Some(key_fingerprint(key))
+    }
+
+    fn name(&self) -> &'static str {
+        "UserToken"
+    }
+}
+
+/// The bearer token of a request, or the `?token=` a registry webhook sends
+/// instead.
+fn token_key(headers: &HeaderMap, query: Option<&str>) -> String {
+    headers
+        .get("Authorization")
+        .and_then(|token| token.to_str().ok())
+        .and_then(|token| token.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_owned())
+        .or_else(|| serde_urlencoded::from_str::<TokenQuery>(query?).ok()?.token)
+        .unwrap_or_default()
+}
+
+/// The token a hook accepts as a bearer token, or as a registry's `?token=`.
+fn bearer_token(command: &TokenCommand) -> Option<&str> {
+    match command {
+        TokenCommand::Token { bearer } => Some(bearer),
+        TokenCommand::Registry { secret, .. } => Some(secret),
+        _ => None,
+    }
+}
+
+fn token_digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+/// Compares a secret token in constant time, so the time taken doesn't
+/// reveal how much of a guess was right. Only the length can leak.
+fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.as_bytes().ct_eq(given.as_bytes()).into()
+}
+
+/// Identifies a rate limiting key in logs without revealing the token.
+fn key_fingerprint(key: &RateLimitKey) -> String {
+    match key {
+        RateLimitKey::Token(digest) => format!("token {}", &hex::encode(digest)[..8]),
+        RateLimitKey::Ip(ip) => ip.to_string(),
+    }
+}
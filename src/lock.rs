@@ -0,0 +1,57 @@
+//! An advisory lock that keeps a second hook on the same host from running
+//! updates at the same time as the first.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, Write},
+    os::fd::AsRawFd,
+    path::Path,
+};
+
+use crate::config::ConfigError;
+
+/// Holds the lock until it is dropped, which happens when the process exits.
+pub struct LockFile {
+    _file: File,
+}
+
+impl LockFile {
+    /// Takes the lock without waiting, and records this process' id in the
+    /// file so the error for a second instance can say who holds it.
+    pub fn acquire(path: &Path) -> Result<Self, ConfigError> {
+        let failed = |e: std::io::Error| {
+            ConfigError::Invalid(format!("failed to lock {}: {}", path.display(), e))
+        };
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(failed)?;
+
+        // SAFETY: the descriptor belongs to the open file
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::WouldBlock {
+                return Err(failed(e));
+            }
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(ConfigError::Invalid(format!(
+                "another instance (pid {}) holds {}, refusing to start",
+                match pid.trim() {
+                    "" => "unknown",
+                    pid => pid,
+                },
+                path.display()
+            )));
+        }
+
+        file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| writeln!(file, "{}", std::process::id()))
+            .map_err(failed)?;
+        Ok(LockFile { _file: file })
+    }
+}
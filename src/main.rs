@@ -1,213 +1,229 @@
-mod headers;
-
-use axum::{
-    error_handling::HandleErrorLayer,
-    extract::{BodyStream, State},
-    headers::{authorization::Bearer, Authorization},
-    http::{Request, StatusCode},
-    routing::post,
-    Json, Router, TypedHeader,
-};
-use clap::{Parser, Subcommand};
-use futures_util::StreamExt;
-use headers::{GithubEvent, GithubSignature256};
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use std::net::SocketAddr;
-use tokio::{process::Command, signal};
-use tower::{BoxError, ServiceBuilder};
-use tower_governor::{
-    errors::display_error, governor::GovernorConfigBuilder, key_extractor::KeyExtractor,
-    GovernorError, GovernorLayer,
+use clap::Parser;
+use futures_util::{future::try_join_all, FutureExt, TryFutureExt};
+use podman_autoupdate_hook::{
+    build_app, config::Config, lock::LockFile, resolve_secrets, App, Opt, Status, Warmup,
 };
+use std::{io::Write, net::SocketAddr, time::Duration};
+use tokio::signal;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
-#[derive(Parser)]
-struct Opt {
-    #[clap(short, long, default_value_t = 5000)]
-    port: u16,
-
-    #[clap(subcommand)]
-    command: Option<TokenCommand>,
-}
-
-#[derive(Subcommand, Clone, Eq, PartialEq)]
-enum TokenCommand {
-    Github { secret: String, events: Vec<String> },
-    Token { bearer: String },
-}
-
-#[derive(Clone)]
-struct Token(Option<TokenCommand>);
-
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
-
+fn main() {
     let opt = Opt::parse();
 
-    match opt.command.as_ref() {
-        Some(TokenCommand::Token { .. }) => {
-            tracing::info!("accepting authorization header");
-        }
-        Some(TokenCommand::Github { events, .. }) => {
-            tracing::info!("accepting github events: {:?}", events);
-        }
-        _ => {}
+    let mut runtime = match opt.current_thread {
+        true => tokio::runtime::Builder::new_current_thread(),
+        false => tokio::runtime::Builder::new_multi_thread(),
+    };
+    if let Some(threads) = opt.worker_threads {
+        runtime.worker_threads(threads);
     }
-
-    let governor_conf = Box::new(
-        GovernorConfigBuilder::default()
-            .per_second(10)
-            .burst_size(5)
-            .key_extractor(UserToken)
-            .use_headers()
-            .finish()
-            .unwrap(),
-    );
-
-    // build our application with a route
-    let app = Router::new()
-        .route("/hook", post(handler))
-        .with_state(Token(opt.command))
-        .layer(
-            ServiceBuilder::new()
-                // this middleware goes above `GovernorLayer` because it will receive
-                // errors returned by `GovernorLayer`
-                .layer(HandleErrorLayer::new(|e: BoxError| async move {
-                    display_error(e)
-                }))
-                .layer(GovernorLayer {
-                    config: Box::leak(governor_conf),
-                }),
-        );
-
-    // run it
-    let addr = SocketAddr::from(([0, 0, 0, 0], opt.port));
-    tracing::info!("listening on {}", addr);
-
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+    let runtime = match runtime.enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("failed to start the runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+    runtime.block_on(run(opt));
 }
 
-async fn handler(
-    State(Token(token)): State<Token>,
-    auth: Option<TypedHeader<Authorization<Bearer>>>,
-    github_signature: Option<TypedHeader<GithubSignature256>>,
-    github_event: Option<TypedHeader<GithubEvent>>,
-    mut stream: BodyStream,
-) -> Result<Json<Vec<AutoUpdateReponse>>, (StatusCode, ())> {
-    match (token, auth, github_signature, github_event) {
-        (Some(TokenCommand::Token { bearer: t1 }), Some(TypedHeader(t2)), None, None)
-            if t1 == t2.token() => {}
-        (Some(TokenCommand::Token { .. }), _, _, _) => {
-            tracing::debug!("token mismatch");
-            return Err((StatusCode::UNAUTHORIZED, ()));
+async fn run(mut opt: Opt) {
+    // with --once, stdout is only for the result so it can be piped
+    let (writer, ansi) = match opt.once {
+        true => (
+            BoxMakeWriter::new(std::io::stderr),
+            opt.color.enabled(&std::io::stderr()),
+        ),
+        false => (
+            BoxMakeWriter::new(std::io::stdout),
+            opt.color.enabled(&std::io::stdout()),
+        ),
+    };
+    tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .init();
+
+    let mut config = match opt.config.as_deref().map(Config::load).transpose() {
+        Ok(config) => config.unwrap_or_default(),
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
         }
-        (
-            Some(TokenCommand::Github { secret, events }),
-            None,
-            Some(TypedHeader(GithubSignature256(signature))),
-            event,
-        ) => {
-            let mut hasher = Sha256::new();
-            hasher.update(secret);
-            while let Some(Ok(b)) = stream.next().await {
-                hasher.update(b);
-            }
+    };
 
-            let (_, signature_exp) = signature
-                .split_once('=')
-                .ok_or((StatusCode::BAD_REQUEST, ()))?;
+    // held until the process exits
+    let _lock = match opt.lock_file.as_deref().map(LockFile::acquire).transpose() {
+        Ok(lock) => lock,
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-            let signature = hex::encode(hasher.finalize());
+    if let Err(e) = resolve_secrets(&mut opt, &mut config).await {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
 
-            if signature != signature_exp {
-                tracing::debug!("github signature mismatch");
-                return Err((StatusCode::UNAUTHORIZED, ()));
-            }
+    let addrs = match &opt.bind[..] {
+        [] => vec![SocketAddr::from(([0, 0, 0, 0], opt.port))],
+        addrs => addrs.to_vec(),
+    };
+    let warmup = opt.warmup;
+    let once = opt.once;
+    let tcp_keepalive = opt.tcp_keepalive;
+    let max_connections = opt.max_connections;
+    let idle_timeout = opt.idle_timeout;
+    #[cfg(feature = "tls")]
+    let tls = match podman_autoupdate_hook::tls::server_config(&opt) {
+        Ok(tls) => tls,
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let App { router, status } = match build_app(opt, config) {
+        Ok(app) => app,
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-            match (&events[..], event) {
-                ([], _) => {}
-                (_, None) => {
-                    tracing::debug!("missing github event header");
-                    return Err((StatusCode::BAD_REQUEST, ()));
-                }
-                (e, Some(TypedHeader(GithubEvent(event)))) if !e.contains(&event) => {
-                    tracing::debug!("github event mismatch, ignoring");
-                    return Err((StatusCode::OK, ()));
+    if once {
+        match status.update_once().await {
+            Ok(body) => {
+                let mut stdout = std::io::stdout().lock();
+                if let Err(e) = stdout.write_all(&body).and_then(|_| writeln!(stdout)) {
+                    tracing::error!("failed to write the result: {}", e);
+                    std::process::exit(1);
                 }
-                _ => {}
+            }
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::process::exit(1);
             }
         }
-        (Some(TokenCommand::Github { .. }), _, None, _) => {
-            tracing::debug!("missing github signature header");
-            return Err((StatusCode::BAD_REQUEST, ()));
-        }
-        _ => {}
+        return;
     }
 
-    tracing::info!("running update");
-
-    let command = match Command::new("podman")
-        .arg("auto-update")
-        .arg("--format")
-        .arg("json")
-        .output()
-        .await
-    {
-        Ok(c) if c.status.success() => c,
-        Err(e) => {
-            tracing::error!("failed to run command: {}", e);
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, ()));
-        }
-        Ok(c) => {
-            tracing::error!(
-                "command failed with status {}: {}",
-                c.status,
-                String::from_utf8_lossy(&c.stderr)
-            );
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, ()));
+    if let Some(warmup) = warmup {
+        if !status.warmup().await && warmup == Warmup::Fail {
+            std::process::exit(1);
         }
-    };
+    }
 
-    tracing::debug!("stdout: {}", String::from_utf8_lossy(&command.stdout));
-    if !command.stderr.is_empty() {
-        tracing::error!("stderr: {}", String::from_utf8_lossy(&command.stderr));
+    #[cfg(unix)]
+    if status.has_keyrings() {
+        tokio::spawn(reload_on_hangup(status.clone()));
     }
 
-    let response: Vec<AutoUpdateReponse> = if command.stdout.starts_with("[".as_bytes()) {
-        serde_json::from_slice(&command.stdout).expect("failed to parse")
-    } else {
-        vec![]
+    // the limit is shared by every listener
+    let router = match max_connections {
+        Some(max) => router.layer(GlobalConcurrencyLimitLayer::new(max)),
+        None => router,
     };
 
-    Ok(Json(response))
+    // run it, with every listener sharing the same routes and state
+    let shutdown = shutdown_signal(status, idle_timeout).shared();
+    let mut servers = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        #[cfg(feature = "tls")]
+        if let Some(config) = &tls {
+            servers.push(serve_tls(
+                addr,
+                config,
+                tcp_keepalive,
+                &router,
+                shutdown.clone(),
+            ));
+            continue;
+        }
+
+        let server = match axum::Server::try_bind(&addr) {
+            Ok(server) => server,
+            Err(e) => {
+                tracing::error!("failed to listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        };
+        tracing::info!("listening on {}", addr);
+        servers.push(
+            server
+                .tcp_keepalive(tcp_keepalive)
+                .serve(
+                    router
+                        .clone()
+                        .into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(shutdown.clone())
+                .map_err(|e| e.to_string())
+                .boxed(),
+        );
+    }
+
+    try_join_all(servers).await.unwrap();
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-struct AutoUpdateReponse {
-    unit: String,
-    container: String,
-    image: String,
-    container_name: String,
-    #[serde(rename = "ContainerID")]
-    container_id: String,
-    policy: String,
-    updated: Updated,
+/// Serves https on `addr` until `shutdown` resolves.
+#[cfg(feature = "tls")]
+fn serve_tls(
+    addr: SocketAddr,
+    config: &std::sync::Arc<rustls::ServerConfig>,
+    tcp_keepalive: Option<Duration>,
+    router: &axum::Router,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> futures_util::future::BoxFuture<'static, Result<(), String>> {
+    let listener = match std::net::TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("failed to listen on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    tracing::info!("listening on {} with tls", addr);
+
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown.await;
+            handle.graceful_shutdown(None);
+        }
+    });
+    axum_server::from_tcp_rustls(
+        listener,
+        axum_server::tls_rustls::RustlsConfig::from_config(config.clone()),
+    )
+    .handle(handle)
+    .addr_incoming_config(
+        axum_server::AddrIncomingConfig::new()
+            .tcp_keepalive(tcp_keepalive)
+            .build(),
+    )
+    .serve(
+        router
+            .clone()
+            .into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .map_err(|e| e.to_string())
+    .boxed()
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum Updated {
-    False,
-    Pending,
+/// Rereads the secret keyrings whenever the process gets a SIGHUP.
+#[cfg(unix)]
+async fn reload_on_hangup(status: Status) {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+        .expect("failed to install signal handler");
+    while hangup.recv().await.is_some() {
+        tracing::info!(signal = "SIGHUP", "reloading keyrings");
+        status.reload_keyrings();
+    }
 }
 
-async fn shutdown_signal() {
+async fn shutdown_signal(status: Status, idle_timeout: Option<Duration>) {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -225,36 +241,39 @@ async fn shutdown_signal() {
     #[cfg(not(unix))]
     let terminate = std::future::pending::<()>();
 
-    tokio::select! {
-        _ = ctrl_c => {},
-        _ = terminate => {},
-    }
-
-    println!("signal received, starting graceful shutdown");
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
-struct UserToken;
-
-impl KeyExtractor for UserToken {
-    type Key = String;
-    type KeyExtractionError = GovernorError;
-
-    fn extract<B>(&self, req: &Request<B>) -> Result<Self::Key, Self::KeyExtractionError> {
-        Ok(req
-            .headers()
-            .get("Authorization")
-            .and_then(|token| token.to_str().ok())
-            .and_then(|token| token.strip_prefix("Bearer "))
-            .map(|token| token.trim().to_owned())
-            .unwrap_or_default())
-    }
+    let idle = async {
+        match idle_timeout {
+            Some(timeout) => {
+                status.idle(timeout).await;
+                timeout
+            }
+            None => std::future::pending().await,
+        }
+    };
 
-    fn key_name(&self, key: &Self::Key) -> Option<String> {
-        Some(key.clone())
-    }
+    let signal = tokio::select! {
+        _ = ctrl_c => "SIGINT",
+        _ = terminate => "SIGTERM",
+        timeout = idle => {
+            // nothing is in flight, or it wouldn't count as idle
+            tracing::info!(
+                "no webhooks for {}, shutting down",
+                humantime::format_duration(timeout)
+            );
+            return;
+        }
+    };
 
-    fn name(&self) -> &'static str {
-        "UserToken"
+    let running = status.updates_running();
+    let waiting = status.updates_waiting();
+    if running + waiting > 0 {
+        tracing::warn!(
+            signal,
+            running,
+            waiting,
+            "shutting down, waiting for updates in flight to finish"
+        );
+    } else {
+        tracing::info!(signal, "shutting down");
     }
 }
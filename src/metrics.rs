@@ -0,0 +1,194 @@
+//! Counters for the hook itself, served at `/metrics` in the prometheus text
+//! exposition format.
+
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header::CONTENT_TYPE, HeaderMap, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use ipnet::IpNet;
+
+use crate::{dashboard::is_local, output::Format, Shared};
+
+/// The upper bounds of the update duration histogram, in seconds.
+const BUCKETS: [f64; 8] = [1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+/// Marks a response as a skipped webhook, so it isn't counted as a success.
+#[derive(Debug, Clone, Copy)]
+pub struct SkippedWebhook;
+
+/// What became of a webhook.
+#[derive(Debug, Clone, Copy)]
+enum Outcome {
+    Success,
+    Skipped,
+    Unauthorized,
+    BadRequest,
+    Failed,
+}
+
+impl Outcome {
+    const ALL: [Outcome; 5] = [
+        Outcome::Success,
+        Outcome::Skipped,
+        Outcome::Unauthorized,
+        Outcome::BadRequest,
+        Outcome::Failed,
+    ];
+
+    fn of(response: &Response) -> Self {
+        let status = response.status();
+        if response.extensions().get::<SkippedWebhook>().is_some() {
+            Outcome::Skipped
+        } else if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            Outcome::Unauthorized
+        } else if status.is_client_error() {
+            Outcome::BadRequest
+        } else if status.is_server_error() {
+            Outcome::Failed
+        } else {
+            Outcome::Success
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Skipped => "skipped",
+            Outcome::Unauthorized => "unauthorized",
+            Outcome::BadRequest => "bad_request",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    webhooks: [AtomicU64; Outcome::ALL.len()],
+    /// How many updates took at most each of the [`BUCKETS`].
+    durations: [AtomicU64; BUCKETS.len()],
+    updates: AtomicU64,
+    update_micros: AtomicU64,
+    containers_updated: AtomicU64,
+}
+
+impl Metrics {
+    /// Records how long running podman took, whether or not it succeeded.
+    pub fn record_update(&self, took: Duration) {
+        self.updates.fetch_add(1, Ordering::Relaxed);
+        self.update_micros
+            .fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+        let seconds = took.as_secs_f64();
+        for (bucket, count) in BUCKETS.iter().zip(&self.durations) {
+            if seconds <= *bucket {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn record_updated(&self, containers: usize) {
+        self.containers_updated
+            .fetch_add(containers as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        let mut metrics = String::from(
+            "# HELP podman_autoupdate_hook_webhooks_total Webhooks received, by outcome.\n\
+             # TYPE podman_autoupdate_hook_webhooks_total counter\n",
+        );
+        for (outcome, count) in Outcome::ALL.iter().zip(&self.webhooks) {
+            let _ = writeln!(
+                metrics,
+                "podman_autoupdate_hook_webhooks_total{{outcome=\"{}\"}} {}",
+                outcome.as_str(),
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        metrics.push_str(
+            "# HELP podman_autoupdate_hook_update_duration_seconds How long running podman took.\n\
+             # TYPE podman_autoupdate_hook_update_duration_seconds histogram\n",
+        );
+        for (bucket, count) in BUCKETS.iter().zip(&self.durations) {
+            let _ = writeln!(
+                metrics,
+                "podman_autoupdate_hook_update_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bucket,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        let updates = self.updates.load(Ordering::Relaxed);
+        let _ = writeln!(
+            metrics,
+            "podman_autoupdate_hook_update_duration_seconds_bucket{{le=\"+Inf\"}} {}\n\
+             podman_autoupdate_hook_update_duration_seconds_sum {}\n\
+             podman_autoupdate_hook_update_duration_seconds_count {}",
+            updates,
+            self.update_micros.load(Ordering::Relaxed) as f64 / 1e6,
+            updates
+        );
+
+        let _ = writeln!(
+            metrics,
+            "# HELP podman_autoupdate_hook_containers_updated_total Containers restarted with a new image.\n\
+             # TYPE podman_autoupdate_hook_containers_updated_total counter\n\
+             podman_autoupdate_hook_containers_updated_total {}",
+            self.containers_updated.load(Ordering::Relaxed)
+        );
+        metrics
+    }
+}
+
+/// Counts the outcome of every webhook posted to the route it wraps.
+pub async fn count_webhooks<B>(
+    State(shared): State<Arc<Shared>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let counted = request.method() == Method::POST;
+    let response = next.run(request).await;
+    if counted {
+        shared.metrics.webhooks[Outcome::of(&response) as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    response
+}
+
+/// Who may scrape `/metrics`.
+pub struct Scrapers {
+    /// Ranges of clients allowed besides the ones on this machine.
+    pub allowed: Vec<IpNet>,
+    pub shared: Arc<Shared>,
+}
+
+/// Serves the metrics to the same clients as the dashboard, and to the
+/// `--metrics-allow` ranges. It isn't rate limited, so it can be scraped as
+/// often as needed.
+pub async fn metrics(
+    State(scrapers): State<Arc<Scrapers>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let shared = &scrapers.shared;
+    let client = shared.proxies.client_ip(peer.ip(), &headers).to_canonical();
+    let allowed = scrapers.allowed.iter().any(|range| range.contains(&client));
+    if !allowed && !is_local(shared, peer, &headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    (
+        [(CONTENT_TYPE, Format::Metrics.content_type())],
+        shared.metrics.render(),
+    )
+        .into_response()
+}
@@ -0,0 +1,169 @@
+//! Best-effort push notifications after an update changed something.
+
+use std::time::Duration;
+
+use crate::{
+    host::hostname,
+    podman::{AutoUpdateReponse, Updated},
+};
+
+/// How long a notification may take before it is given up on.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// Publish to an ntfy topic, e.g. `https://ntfy.sh/my-topic`.
+    Ntfy(String),
+    /// Post to a gotify server's `/message` endpoint with an app token.
+    Gotify { url: String, token: String },
+    /// Post to a slack incoming webhook, as an attachment.
+    Slack(String),
+    /// Post to a discord channel webhook, as an embed.
+    Discord(String),
+}
+
+/// Chat platforms cap how many fields a message can have (discord at 25),
+/// so only the first containers are listed.
+const MAX_FIELDS: usize = 25;
+
+/// What a notification says: the summary line, and the containers it is
+/// about for the targets that can lay them out.
+struct Message {
+    summary: String,
+    containers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    client: reqwest::Client,
+    targets: Vec<Target>,
+    host: String,
+}
+
+impl Notifier {
+    pub fn new(targets: Vec<Target>) -> Option<Self> {
+        if targets.is_empty() {
+            return None;
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .expect("the tls backend is available");
+        Some(Notifier {
+            client,
+            targets,
+            host: hostname(),
+        })
+    }
+
+    /// Sends a summary of the update in the background if any container was
+    /// restarted. Failures are only logged.
+    pub fn notify(&self, response: &[AutoUpdateReponse]) {
+        if let Some(summary) = summary(response) {
+            let containers = response
+                .iter()
+                .filter(|r| r.updated == Updated::True)
+                .take(MAX_FIELDS)
+                .map(|r| (r.container_name.clone(), r.image.clone()))
+                .collect();
+            self.send_all(Message {
+                summary,
+                containers,
+            });
+        }
+    }
+
+    /// Sends a notification that a webhook arrived for an event that is
+    /// mapped to `notify` instead of an update.
+    pub fn notify_event(&self, event: &str) {
+        self.send_all(Message {
+            summary: format!("Received a {} event", event),
+            containers: Vec::new(),
+        });
+    }
+
+    fn send_all(&self, message: Message) {
+        let notifier = self.clone();
+        tokio::spawn(async move {
+            let title = format!("podman-autoupdate-hook on {}", notifier.host);
+            for target in &notifier.targets {
+                if let Err(e) = notifier.send(target, &title, &message).await {
+                    tracing::warn!("failed to send notification: {}", e);
+                }
+            }
+        });
+    }
+
+    async fn send(&self, target: &Target, title: &str, message: &Message) -> reqwest::Result<()> {
+        let request = match target {
+            Target::Ntfy(url) => self
+                .client
+                .post(url)
+                .header("Title", title)
+                .body(message.summary.clone()),
+            Target::Gotify { url, token } => self
+                .client
+                .post(format!("{}/message", url.trim_end_matches('/')))
+                .header("X-Gotify-Key", token)
+                .json(&serde_json::json!({ "title": title, "message": message.summary })),
+            Target::Slack(url) => self.client.post(url).json(&slack(title, message)),
+            Target::Discord(url) => self.client.post(url).json(&discord(title, message)),
+        };
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// A one line description of the containers that were restarted, such as
+/// `Updated 2 containers: nginx, grafana`.
+pub fn summary(response: &[AutoUpdateReponse]) -> Option<String> {
+    let updated: Vec<_> = response
+        .iter()
+        .filter(|r| r.updated == Updated::True)
+        .map(|r| r.container_name.as_str())
+        .collect();
+
+    match updated.len() {
+        0 => None,
+        1 => Some(format!("Updated 1 container: {}", updated[0])),
+        n => Some(format!("Updated {} containers: {}", n, updated.join(", "))),
+    }
+}
+
+/// A slack message with the containers as the fields of an attachment. The
+/// top level `text` is what shows up in the notification itself.
+fn slack(title: &str, message: &Message) -> serde_json::Value {
+    let fields: Vec<_> = message
+        .containers
+        .iter()
+        .map(|(name, image)| serde_json::json!({ "title": name, "value": image, "short": true }))
+        .collect();
+    serde_json::json!({
+        "text": message.summary,
+        "attachments": [{
+            "color": "good",
+            "title": title,
+            "text": message.summary,
+            "fields": fields,
+        }],
+    })
+}
+
+/// A discord message with the containers as the fields of an embed.
+fn discord(title: &str, message: &Message) -> serde_json::Value {
+    let fields: Vec<_> = message
+        .containers
+        .iter()
+        .map(|(name, image)| serde_json::json!({ "name": name, "value": image, "inline": true }))
+        .collect();
+    serde_json::json!({
+        "username": "podman-autoupdate-hook",
+        "embeds": [{
+            "title": title,
+            "description": message.summary,
+            "color": 0x2e_cc_71,
+            "fields": fields,
+        }],
+    })
+}
@@ -0,0 +1,128 @@
+//! The formats an update result can be sent in, picked from the request's
+//! `Accept` header.
+
+use std::fmt::Write;
+
+use crate::podman::{AutoUpdateReponse, Updated};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    Json,
+    /// One tab separated line per container.
+    Text,
+    /// The prometheus text exposition format.
+    Metrics,
+}
+
+impl Format {
+    /// Picks the `supported` format the client prefers most, defaulting to
+    /// JSON without an `Accept` header. `None` means none of the accepted
+    /// types are supported.
+    pub fn negotiate(accept: Option<&str>, supported: &[Format]) -> Option<Self> {
+        let Some(accept) = accept else {
+            return Some(Format::Json);
+        };
+
+        let mut best: Option<(f32, Format)> = None;
+        for range in accept.split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+            let mut quality = 1.0;
+            let mut version = None;
+            for param in params {
+                match param.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+                    Some(("q", q)) => quality = q.parse().unwrap_or(0.0),
+                    Some(("version", v)) => version = Some(v),
+                    _ => {}
+                }
+            }
+
+            let format = match (media_type.as_str(), version) {
+                ("application/json" | "application/*" | "*/*", _) => Format::Json,
+                // prometheus asks for its format as a versioned text/plain
+                ("text/plain", Some("0.0.4")) | ("application/openmetrics-text", _) => {
+                    Format::Metrics
+                }
+                ("text/plain" | "text/*", _) => Format::Text,
+                _ => continue,
+            };
+            if !supported.contains(&format) {
+                continue;
+            }
+            // the first of equally preferred types wins
+            if quality > 0.0 && best.is_none_or(|(q, _)| quality > q) {
+                best = Some((quality, format));
+            }
+        }
+        best.map(|(_, format)| format)
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Text => "text/plain; charset=utf-8",
+            Format::Metrics => "text/plain; version=0.0.4; charset=utf-8",
+        }
+    }
+}
+
+/// A line per container with its name, image and whether it was updated.
+pub fn text(response: &[AutoUpdateReponse]) -> String {
+    response.iter().fold(String::new(), |mut text, c| {
+        let _ = writeln!(
+            text,
+            "{}\t{}\t{}",
+            c.container_name,
+            c.image,
+            c.updated.as_str()
+        );
+        text
+    })
+}
+
+/// The result as prometheus gauges: how many containers ended up in each
+/// state, and one series per container.
+pub fn metrics(response: &[AutoUpdateReponse]) -> String {
+    let mut metrics = String::from(
+        "# HELP podman_autoupdate_containers Containers checked by the update, by result.\n\
+         # TYPE podman_autoupdate_containers gauge\n",
+    );
+    for updated in [
+        Updated::True,
+        Updated::False,
+        Updated::Pending,
+        Updated::Failed,
+        Updated::RolledBack,
+    ] {
+        let count = response.iter().filter(|c| c.updated == updated).count();
+        let _ = writeln!(
+            metrics,
+            "podman_autoupdate_containers{{updated=\"{}\"}} {}",
+            updated.as_str(),
+            count
+        );
+    }
+
+    metrics.push_str(
+        "# HELP podman_autoupdate_container A container checked by the update.\n\
+         # TYPE podman_autoupdate_container gauge\n",
+    );
+    for c in response {
+        let _ = writeln!(
+            metrics,
+            "podman_autoupdate_container{{container=\"{}\",image=\"{}\",updated=\"{}\"}} 1",
+            label(&c.container_name),
+            label(&c.image),
+            c.updated.as_str()
+        );
+    }
+    metrics
+}
+
+/// Escapes a label value for the exposition format.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,1245 @@
+use hyper::{client::HttpConnector, Body, Client, Method, Request, Uri};
+use hyperlocal::UnixConnector;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fmt,
+    future::Future,
+    path::PathBuf,
+    process::Output,
+    time::Duration,
+};
+use tokio::process::Command;
+
+use crate::{config::ContainerPolicy, image::ImageReference, registry, SystemdScope};
+
+/// The label podman uses to opt containers into auto-updates.
+const AUTOUPDATE_LABEL: &str = "io.containers.autoupdate";
+
+/// The label podman sets on containers started by a systemd unit.
+const UNIT_LABEL: &str = "PODMAN_SYSTEMD_UNIT";
+
+/// How often `--verify-health` checks on a container that is still starting.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A container reported by an update. Fields that don't apply to a runtime
+/// (such as the systemd unit for docker) are left empty.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AutoUpdateReponse {
+    #[serde(alias = "unit", default)]
+    pub unit: String,
+    #[serde(alias = "container")]
+    pub container: String,
+    #[serde(alias = "image")]
+    pub image: String,
+    #[serde(alias = "container_name", alias = "containerName")]
+    pub container_name: String,
+    #[serde(
+        rename = "ContainerID",
+        alias = "ContainerId",
+        alias = "container_id",
+        alias = "containerId",
+        alias = "containerID"
+    )]
+    pub container_id: String,
+    #[serde(alias = "policy")]
+    pub policy: Policy,
+    #[serde(alias = "updated")]
+    pub updated: Updated,
+    /// The parts of `image`, only filled in with `--image-details`.
+    #[serde(flatten, skip_deserializing)]
+    pub reference: Option<ImageReference>,
+    /// Whether the image was pulled by `--force-pull` before the update.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub force_pulled: bool,
+    /// How the container's healthcheck ended up after the update, only
+    /// filled in with `--verify-health` for containers that have one.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub health: Option<Health>,
+}
+
+/// The containers an update covered, and the ones a filter left out.
+#[derive(Debug, Clone, Default)]
+pub struct Update {
+    pub containers: Vec<AutoUpdateReponse>,
+    pub skipped: Vec<Skipped>,
+    /// Podman's output as it printed it, with `--passthrough`. The other
+    /// fields are empty then, since the output isn't parsed.
+    pub raw: Option<Vec<u8>>,
+}
+
+/// A container that was left out of an update, and why.
+#[derive(Debug, Clone, Serialize)]
+pub struct Skipped {
+    pub container: String,
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SkipReason {
+    /// Its policy in the config file is `ignore`.
+    Ignored,
+    /// It doesn't have the `--filter-label` labels.
+    LabelFilter,
+    /// Its unit isn't in `--allow-unit`.
+    UnitAllowlist,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    Healthy,
+    /// The healthcheck failed, or didn't pass within the timeout.
+    Unhealthy,
+}
+
+/// The `io.containers.autoupdate` policy of a container.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    /// Pull the image from its registry (`image` is podman's older name for it).
+    #[serde(alias = "image")]
+    Registry,
+    /// Use an image that was built or pulled locally.
+    Local,
+    /// A docker compose service, which has no podman policy.
+    Compose,
+    /// A policy this version of the hook doesn't know about.
+    #[serde(other)]
+    Unknown,
+}
+
+/// The `Updated` column, serialized as exactly the strings podman prints so
+/// responses can be compared byte for byte with podman's own output.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Updated {
+    #[serde(rename = "true")]
+    True,
+    #[serde(rename = "false")]
+    False,
+    /// A newer image is available (reported by `--dry-run`).
+    #[serde(rename = "pending")]
+    Pending,
+    /// The update failed and the container was left as it was.
+    #[serde(rename = "failed")]
+    Failed,
+    /// The updated container failed to start and the old image was restored.
+    #[serde(rename = "rolled back")]
+    RolledBack,
+}
+
+impl Updated {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Updated::True => "true",
+            Updated::False => "false",
+            Updated::Pending => "pending",
+            Updated::Failed => "failed",
+            Updated::RolledBack => "rolled back",
+        }
+    }
+}
+
+impl AutoUpdateReponse {
+    /// Whether podman found a newer image for this container and updated it
+    /// (or would have, in a dry run). A failed or rolled back update changed
+    /// nothing.
+    pub fn changed(&self) -> bool {
+        matches!(self.updated, Updated::True | Updated::Pending)
+    }
+}
+
+#[derive(Debug)]
+pub enum RunError {
+    Spawn(std::io::Error),
+    Failed {
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
+    Parse(serde_json::Error),
+    Api(String),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Spawn(e) => write!(f, "failed to run command: {}", e),
+            RunError::Failed { status, stderr } => {
+                write!(f, "command failed with status {}: {}", status, stderr)
+            }
+            RunError::Parse(e) => write!(f, "failed to parse podman output: {}", e),
+            RunError::Api(e) => write!(f, "podman api request failed: {}", e),
+        }
+    }
+}
+
+impl RunError {
+    /// Whether trying again might succeed, e.g. when the registry is flaky.
+    fn is_transient(&self) -> bool {
+        matches!(self, RunError::Failed { .. } | RunError::Api(_))
+    }
+}
+
+/// How failed updates are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    pub retries: u32,
+    pub delay: Duration,
+    pub max_delay: Duration,
+    /// Seeds the jitter, so the delays are the same every time. Without it
+    /// the jitter comes from the os.
+    pub seed: Option<u64>,
+}
+
+impl Retry {
+    /// Exponential backoff from `delay` up to `max_delay`, with "full" jitter:
+    /// a random wait between zero and the backoff, so hooks sharing a
+    /// registry don't all retry at the same moment.
+    pub fn backoff(&self, attempt: u32, rng: &mut impl Rng) -> Duration {
+        let ceiling = self
+            .delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        ceiling.mul_f64(rng.gen::<f64>())
+    }
+
+    /// Where the jitter comes from.
+    pub fn rng(&self) -> StdRng {
+        self.seed
+            .map_or_else(StdRng::from_entropy, StdRng::seed_from_u64)
+    }
+}
+
+/// How the hook talks to podman.
+pub enum Runner {
+    /// Shell out to `podman auto-update`.
+    Cli(Cli),
+    /// Use the podman REST API, for when the cli isn't available.
+    Api(ApiClient),
+    /// Pull and recreate a docker compose project.
+    Docker(Cli),
+}
+
+impl Runner {
+    pub async fn auto_update(&self, args: &[String]) -> Result<Vec<AutoUpdateReponse>, RunError> {
+        match self {
+            Runner::Cli(cli) => cli.auto_update(args, false).await,
+            Runner::Api(client) => client.auto_update(false).await,
+            Runner::Docker(cli) => cli.compose_update("docker", args).await,
+        }
+    }
+
+    /// Reports what an update would do without restarting anything. Docker
+    /// compose has no dry run, so its containers are only listed.
+    pub async fn dry_run(&self, args: &[String]) -> Result<Vec<AutoUpdateReponse>, RunError> {
+        match self {
+            Runner::Cli(cli) => cli.auto_update(args, true).await,
+            Runner::Api(client) => client.auto_update(true).await,
+            Runner::Docker(cli) => Ok(cli
+                .compose_ps("docker", args)
+                .await?
+                .into_iter()
+                .map(|c| c.into_response(Updated::False))
+                .collect()),
+        }
+    }
+
+    /// The version of the container engine, as it describes itself.
+    pub async fn version(&self) -> Result<String, RunError> {
+        match self {
+            Runner::Cli(cli) => cli.version(&cli.podman).await,
+            Runner::Api(client) => client.version().await,
+            Runner::Docker(cli) => cli.version("docker").await,
+        }
+    }
+
+    /// Runs [`Runner::update_with_policies`], retrying transient failures.
+    pub async fn update(
+        &self,
+        args: &[String],
+        policies: &HashMap<String, ContainerPolicy>,
+        retry: Retry,
+    ) -> Result<Update, RunError> {
+        retrying(retry, || self.update_with_policies(args, policies)).await
+    }
+
+    /// Updates containers one at a time according to their configured
+    /// policy, using a dry run to find the candidates.
+    ///
+    /// Containers without a policy are updated immediately, matching the
+    /// behaviour of a plain `podman auto-update`.
+    pub async fn update_with_policies(
+        &self,
+        args: &[String],
+        policies: &HashMap<String, ContainerPolicy>,
+    ) -> Result<Update, RunError> {
+        let pulled = match self {
+            Runner::Cli(cli) if cli.force_pull && !cli.dry_run => cli.pull_all(args).await?,
+            _ => HashSet::new(),
+        };
+        let mark_pulled = |mut response: Vec<AutoUpdateReponse>| {
+            for container in &mut response {
+                container.force_pulled = pulled.contains(&container.image);
+            }
+            response
+        };
+
+        let only_on_change = matches!(self, Runner::Cli(cli) if cli.restart_only_on_change);
+        let labelled = match self {
+            Runner::Cli(cli) if !cli.filter_labels.is_empty() => Some(cli.labelled().await?),
+            _ => None,
+        };
+        let allowlist = matches!(self, Runner::Cli(cli) if !cli.allowed_units.is_empty());
+        if policies.is_empty() && !only_on_change && labelled.is_none() && !allowlist {
+            return Ok(Update {
+                containers: mark_pulled(self.auto_update(args).await?),
+                ..Update::default()
+            });
+        }
+
+        let mut candidates = match self {
+            Runner::Cli(cli) => cli.auto_update(args, true).await?,
+            Runner::Api(client) => client.auto_update(true).await?,
+            Runner::Docker(cli) => cli.compose_update("docker", args).await?,
+        };
+
+        let policy = |c: &AutoUpdateReponse| {
+            policies
+                .get(&c.container_name)
+                .or_else(|| policies.get(&c.image))
+                .copied()
+                .unwrap_or_default()
+        };
+
+        let mut skipped = vec![];
+        candidates.retain(|c| {
+            let reason = if policy(c) == ContainerPolicy::Ignore {
+                tracing::debug!("ignoring container {}", c.container_name);
+                SkipReason::Ignored
+            } else if labelled
+                .as_ref()
+                .is_some_and(|names| !names.contains(&c.container_name))
+            {
+                tracing::debug!("container {} doesn't match the labels", c.container_name);
+                SkipReason::LabelFilter
+            } else if matches!(self, Runner::Cli(cli) if !cli.unit_allowed(&c.unit)) {
+                if c.changed() {
+                    tracing::warn!(
+                        "container {} has an update, but its unit {:?} isn't allowed, skipping",
+                        c.container_name,
+                        c.unit
+                    );
+                } else {
+                    tracing::debug!("unit {:?} isn't allowed", c.unit);
+                }
+                SkipReason::UnitAllowlist
+            } else {
+                return true;
+            };
+            skipped.push(Skipped {
+                container: c.container_name.clone(),
+                reason,
+            });
+            false
+        });
+
+        if let Runner::Api(client) = self {
+            for candidate in &mut candidates {
+                if policy(candidate) == ContainerPolicy::Immediate
+                    && candidate.updated == Updated::Pending
+                {
+                    client.update_container(candidate).await;
+                }
+            }
+        }
+        if let Runner::Cli(cli) = self {
+            let scope = SystemdScope::current();
+            for candidate in &mut candidates {
+                // a dry run only reports the candidates
+                if cli.dry_run || policy(candidate) != ContainerPolicy::Immediate {
+                    continue;
+                }
+                if cli.restart_only_on_change {
+                    cli.restart_if_changed(candidate, scope).await?;
+                } else if candidate.updated == Updated::Pending {
+                    cli.restart(candidate, scope).await?;
+                }
+            }
+        }
+
+        Ok(Update {
+            containers: mark_pulled(candidates),
+            skipped,
+            raw: None,
+        })
+    }
+}
+
+/// Runs `update` until it succeeds, fails for good or runs out of retries.
+async fn retrying<T, F>(retry: Retry, mut update: impl FnMut() -> F) -> Result<T, RunError>
+where
+    F: Future<Output = Result<T, RunError>>,
+{
+    let mut rng = retry.rng();
+    let mut attempt = 0;
+    loop {
+        match update().await {
+            Err(e) if e.is_transient() && attempt < retry.retries => {
+                let delay = retry.backoff(attempt, &mut rng);
+                tracing::warn!("update failed, retrying in {:?}: {}", delay, e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// What podman's cli prints when `auto-update` has no `--format` flag.
+const FORMAT_UNSUPPORTED: &str = "unknown flag: --format";
+
+fn log_output(output: &Output) {
+    tracing::debug!("stdout: {}", String::from_utf8_lossy(&output.stdout));
+    if !output.stderr.is_empty() {
+        tracing::error!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    }
+}
+
+/// Runs a command, treating a non-zero exit status as an error.
+async fn run(command: &mut Command) -> Result<Output, RunError> {
+    run_accepting(command, &[0]).await
+}
+
+/// Runs a command, treating any exit status but the `success` codes as an
+/// error.
+async fn run_accepting(command: &mut Command, success: &[i32]) -> Result<Output, RunError> {
+    match command.output().await {
+        Ok(c) if c.status.code().is_some_and(|code| success.contains(&code)) => {
+            if !c.status.success() {
+                tracing::debug!("{} counts as success", c.status);
+            }
+            Ok(c)
+        }
+        Err(e) => Err(RunError::Spawn(e)),
+        Ok(c) => Err(RunError::Failed {
+            status: c.status,
+            stderr: String::from_utf8_lossy(&c.stderr).into_owned(),
+        }),
+    }
+}
+
+/// Options for running the podman cli.
+#[derive(Debug)]
+pub struct Cli {
+    /// The podman binary to run.
+    pub podman: PathBuf,
+    /// The working directory for spawned commands.
+    pub workdir: Option<PathBuf>,
+    /// Extra environment variables for spawned commands.
+    pub env: Vec<(String, String)>,
+    /// Parse podman's table output when there is no JSON.
+    pub parse_table_fallback: bool,
+    /// Pull images first and only restart containers whose image changed.
+    pub restart_only_on_change: bool,
+    /// Pull every registry image before updating, in case podman's view of
+    /// the registry is stale.
+    pub force_pull: bool,
+    /// The exit codes of `podman auto-update` that aren't failures.
+    pub success_exit_codes: Vec<i32>,
+    /// Only update containers with all of these labels (`key=value`).
+    pub filter_labels: Vec<String>,
+    /// Take a whole compose project down and up again when any of its
+    /// images changed, instead of recreating single services.
+    pub restart_project_on_change: bool,
+    /// The only units that may be restarted, either exact names or prefixes
+    /// ending in `*`. Empty allows every unit.
+    pub allowed_units: Vec<String>,
+    /// Pass `--dry-run` to every `podman auto-update`, so nothing is pulled
+    /// or restarted and updates are only reported as `pending`.
+    pub dry_run: bool,
+}
+
+impl Cli {
+    fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        let mut command = Command::new(program);
+        if let Some(workdir) = &self.workdir {
+            command.current_dir(workdir);
+        }
+        command.envs(self.env.iter().map(|(k, v)| (k, v)));
+        command
+    }
+
+    async fn version(&self, program: impl AsRef<OsStr>) -> Result<String, RunError> {
+        let output = run(self.command(program).arg("--version")).await?;
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    }
+
+    fn unit_allowed(&self, unit: &str) -> bool {
+        self.allowed_units.is_empty()
+            || self
+                .allowed_units
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => unit.starts_with(prefix),
+                    None => unit == pattern,
+                })
+    }
+
+    /// The names of the running containers with every `--filter-label`.
+    async fn labelled(&self) -> Result<HashSet<String>, RunError> {
+        let mut command = self.command(&self.podman);
+        command.args(["ps", "--format", "{{.Names}}"]);
+        for label in &self.filter_labels {
+            command.arg("--filter").arg(format!("label={}", label));
+        }
+        let output = run(&mut command).await?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned)
+            .collect())
+    }
+
+    /// Pulls the image of every container with the `registry` policy, so a
+    /// mutable tag is picked up even if podman thinks nothing changed.
+    async fn pull_all(&self, args: &[String]) -> Result<HashSet<String>, RunError> {
+        let mut pulled = HashSet::new();
+        for container in self.auto_update(args, true).await? {
+            if container.policy != Policy::Registry || pulled.contains(&container.image) {
+                continue;
+            }
+            tracing::info!("force pulling {}", container.image);
+            run(self.command(&self.podman).arg("pull").arg(&container.image)).await?;
+            pulled.insert(container.image);
+        }
+        Ok(pulled)
+    }
+
+    /// Pulls the new image for a container and restarts its unit, which is what
+    /// `podman auto-update` does for each container it updates.
+    async fn restart(
+        &self,
+        container: &mut AutoUpdateReponse,
+        scope: SystemdScope,
+    ) -> Result<(), RunError> {
+        if container.unit.is_empty() {
+            tracing::warn!(
+                "container {} has no systemd unit, not restarting",
+                container.container_name
+            );
+            return Ok(());
+        }
+
+        tracing::info!("updating container {}", container.container_name);
+        run(self.command(&self.podman).arg("pull").arg(&container.image)).await?;
+        self.restart_unit(container, scope).await
+    }
+
+    /// Pulls the image for a container (unless its policy is `local`) and only
+    /// restarts the unit if the container is running a different image ID
+    /// than the one the tag now points to.
+    async fn restart_if_changed(
+        &self,
+        container: &mut AutoUpdateReponse,
+        scope: SystemdScope,
+    ) -> Result<(), RunError> {
+        if container.unit.is_empty() {
+            tracing::warn!(
+                "container {} has no systemd unit, not restarting",
+                container.container_name
+            );
+            return Ok(());
+        }
+
+        let running = self
+            .inspect(
+                &self.podman,
+                "container",
+                &container.container_id,
+                "{{.Image}}",
+            )
+            .await?;
+        if container.policy != Policy::Local {
+            run(self.command(&self.podman).arg("pull").arg(&container.image)).await?;
+        }
+        let latest = self
+            .inspect(&self.podman, "image", &container.image, "{{.Id}}")
+            .await?;
+
+        if running == latest {
+            tracing::debug!(
+                "container {} already runs the latest image",
+                container.container_name
+            );
+            container.updated = Updated::False;
+            return Ok(());
+        }
+
+        tracing::info!("updating container {}", container.container_name);
+        self.restart_unit(container, scope).await
+    }
+
+    async fn restart_unit(
+        &self,
+        container: &mut AutoUpdateReponse,
+        scope: SystemdScope,
+    ) -> Result<(), RunError> {
+        let mut systemctl = self.command("systemctl");
+        if scope == SystemdScope::User {
+            systemctl.arg("--user");
+        }
+        run(systemctl.arg("restart").arg(&container.unit)).await?;
+
+        container.updated = Updated::True;
+        Ok(())
+    }
+
+    /// Runs `<program> <kind> inspect` with a go template, e.g. to get an ID.
+    async fn inspect(
+        &self,
+        program: impl AsRef<OsStr>,
+        kind: &str,
+        name: &str,
+        format: &str,
+    ) -> Result<String, RunError> {
+        let output = run(self
+            .command(program)
+            .arg(kind)
+            .arg("inspect")
+            .arg("--format")
+            .arg(format)
+            .arg(name))
+        .await?;
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    }
+
+    /// Waits for the healthcheck of every restarted container to pass, for up
+    /// to `timeout`. Containers without a healthcheck are left alone.
+    pub async fn verify_health(&self, containers: &mut [AutoUpdateReponse], timeout: Duration) {
+        let checks = containers
+            .iter_mut()
+            .filter(|c| c.updated == Updated::True)
+            .map(|container| async move {
+                // the update replaces the container, so only the name is stable
+                container.health = self.wait_healthy(&container.container_name, timeout).await;
+                if container.health == Some(Health::Unhealthy) {
+                    tracing::warn!(
+                        "{} is not healthy after the update",
+                        container.container_name
+                    );
+                }
+            });
+        futures_util::future::join_all(checks).await;
+    }
+
+    async fn wait_healthy(&self, name: &str, timeout: Duration) -> Option<Health> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let status = self
+                .inspect(&self.podman, "container", name, "{{.State.Health.Status}}")
+                .await;
+            match status.as_deref() {
+                Ok("healthy") => return Some(Health::Healthy),
+                Ok("unhealthy") => return Some(Health::Unhealthy),
+                // still starting, or the unit hasn't recreated it yet
+                Ok("starting") | Err(_) => {}
+                Ok(_) => return None,
+            }
+            if tokio::time::Instant::now() + HEALTH_INTERVAL > deadline {
+                return Some(Health::Unhealthy);
+            }
+            tokio::time::sleep(HEALTH_INTERVAL).await;
+        }
+    }
+
+    /// Runs a plain `podman auto-update` and returns its JSON output without
+    /// parsing it, for `--passthrough`. Transient failures are retried.
+    pub async fn auto_update_raw(
+        &self,
+        args: &[String],
+        retry: Retry,
+    ) -> Result<Vec<u8>, RunError> {
+        retrying(retry, || async {
+            let mut command = self.command(&self.podman);
+            command.arg("auto-update").args(["--format", "json"]);
+            if self.dry_run {
+                command.arg("--dry-run");
+            }
+            command.args(args);
+            let output = run_accepting(&mut command, &self.success_exit_codes).await?;
+            log_output(&output);
+            Ok(output.stdout)
+        })
+        .await
+    }
+
+    async fn auto_update(
+        &self,
+        args: &[String],
+        dry_run: bool,
+    ) -> Result<Vec<AutoUpdateReponse>, RunError> {
+        let auto_update = |json: bool| {
+            let mut command = self.command(&self.podman);
+            command.arg("auto-update");
+            if json {
+                command.arg("--format").arg("json");
+            }
+            if dry_run || self.dry_run {
+                command.arg("--dry-run");
+            }
+            command.args(args);
+            command
+        };
+
+        let command = match run_accepting(&mut auto_update(true), &self.success_exit_codes).await {
+            Err(RunError::Failed { stderr, .. }) if stderr.contains(FORMAT_UNSUPPORTED) => {
+                tracing::warn!(
+                    "this podman can't print auto-update results as json, falling back to \
+                     its table output; upgrade podman to avoid this"
+                );
+                let command =
+                    run_accepting(&mut auto_update(false), &self.success_exit_codes).await?;
+                log_output(&command);
+                return match parse_table(&command.stdout) {
+                    Some(response) => Ok(response),
+                    // no table at all means nothing was updated
+                    None => parse_output(&command.stdout).map_err(RunError::Parse),
+                };
+            }
+            result => result?,
+        };
+        log_output(&command);
+
+        match parse_output(&command.stdout) {
+            Ok(response) if !response.is_empty() => Ok(response),
+            result if self.parse_table_fallback => match parse_table(&command.stdout) {
+                Some(response) => {
+                    tracing::debug!("parsed podman table output");
+                    Ok(response)
+                }
+                None => result.map_err(RunError::Parse),
+            },
+            result => result.map_err(RunError::Parse),
+        }
+    }
+
+    /// Pulls and recreates a compose project, reporting the containers that
+    /// were replaced. `args` go before the compose subcommand, e.g. `-f`.
+    async fn compose_update(
+        &self,
+        program: &str,
+        args: &[String],
+    ) -> Result<Vec<AutoUpdateReponse>, RunError> {
+        let before = self.compose_ps(program, args).await?;
+        run(self.command(program).arg("compose").args(args).arg("pull")).await?;
+        if self.restart_project_on_change && self.compose_changed(program, &before).await? {
+            tracing::info!("an image changed, restarting the whole compose project");
+            run(self.command(program).arg("compose").args(args).arg("down")).await?;
+        }
+        run(self
+            .command(program)
+            .arg("compose")
+            .args(args)
+            .args(["up", "--detach"]))
+        .await?;
+        let after = self.compose_ps(program, args).await?;
+
+        Ok(after
+            .into_iter()
+            .map(|c| {
+                let updated = if before.iter().any(|b| b.id == c.id) {
+                    Updated::False
+                } else {
+                    Updated::True
+                };
+                c.into_response(updated)
+            })
+            .collect())
+    }
+
+    /// Whether any of the containers runs a different image than its tag
+    /// points to, i.e. whether the last pull got something new.
+    async fn compose_changed(
+        &self,
+        program: &str,
+        containers: &[ComposeContainer],
+    ) -> Result<bool, RunError> {
+        for container in containers {
+            let running = self
+                .inspect(program, "container", &container.id, "{{.Image}}")
+                .await?;
+            let latest = self
+                .inspect(program, "image", &container.image, "{{.Id}}")
+                .await?;
+            if running != latest {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn compose_ps(
+        &self,
+        program: &str,
+        args: &[String],
+    ) -> Result<Vec<ComposeContainer>, RunError> {
+        let output = run(self
+            .command(program)
+            .arg("compose")
+            .args(args)
+            .args(["ps", "--format", "json"]))
+        .await?;
+
+        // older versions print one array, newer ones one object per line
+        let mut containers = vec![];
+        for value in serde_json::Deserializer::from_slice(&output.stdout).into_iter() {
+            match value.map_err(RunError::Parse)? {
+                serde_json::Value::Array(values) => {
+                    for value in values {
+                        containers.push(serde_json::from_value(value).map_err(RunError::Parse)?);
+                    }
+                }
+                value => containers.push(serde_json::from_value(value).map_err(RunError::Parse)?),
+            }
+        }
+        Ok(containers)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ComposeContainer {
+    #[serde(rename = "ID")]
+    id: String,
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Image")]
+    image: String,
+}
+
+impl ComposeContainer {
+    fn into_response(self, updated: Updated) -> AutoUpdateReponse {
+        AutoUpdateReponse {
+            unit: String::new(),
+            container: format!("{} ({})", &self.id[..12.min(self.id.len())], self.name),
+            image: self.image,
+            container_name: self.name,
+            container_id: self.id,
+            policy: Policy::Compose,
+            updated,
+            reference: None,
+            force_pulled: false,
+            health: None,
+        }
+    }
+}
+
+/// Finds the JSON array in podman's stdout, skipping any pull progress that
+/// was printed before it. No output at all means nothing was updated.
+fn parse_output(stdout: &[u8]) -> Result<Vec<AutoUpdateReponse>, serde_json::Error> {
+    let mut error = None;
+    let mut offset = 0;
+    for line in stdout.split_inclusive(|&b| b == b'\n') {
+        let start = offset + line.iter().take_while(|b| b.is_ascii_whitespace()).count();
+        offset += line.len();
+
+        if stdout.get(start) != Some(&b'[') {
+            continue;
+        }
+
+        // progress bars also start with `[`, so keep looking if this isn't it
+        match serde_json::Deserializer::from_slice(&stdout[start..])
+            .into_iter()
+            .next()
+        {
+            Some(Ok(response)) => return Ok(response),
+            Some(Err(e)) => error = Some(e),
+            None => {}
+        }
+    }
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(vec![]),
+    }
+}
+
+/// Parses the default `podman auto-update` table, for podman builds where
+/// `--format json` doesn't work:
+///
+/// ```text
+/// UNIT                   CONTAINER           IMAGE                           POLICY      UPDATED
+/// container-web.service  0123456789ab (web)  docker.io/library/nginx:latest  registry    false
+/// ```
+///
+/// Columns are sliced at the offsets of the header names, since values such
+/// as `rolled back` contain spaces.
+fn parse_table(stdout: &[u8]) -> Option<Vec<AutoUpdateReponse>> {
+    const COLUMNS: [&str; 5] = ["UNIT", "CONTAINER", "IMAGE", "POLICY", "UPDATED"];
+
+    let stdout = std::str::from_utf8(stdout).ok()?;
+    let mut lines = stdout.lines().skip_while(|l| !l.starts_with("UNIT"));
+    let header = lines.next()?;
+
+    let mut offsets = [0; 5];
+    let mut from = 0;
+    for (offset, column) in offsets.iter_mut().zip(COLUMNS) {
+        *offset = from + header[from..].find(column)?;
+        from = *offset + column.len();
+    }
+
+    let field = |line: &str, i: usize| {
+        let end = offsets.get(i + 1).copied().unwrap_or(line.len());
+        line.get(offsets[i]..end.min(line.len()))
+            .unwrap_or_default()
+            .trim()
+            .to_owned()
+    };
+
+    let mut response = vec![];
+    for line in lines.filter(|l| !l.trim().is_empty()) {
+        let container = field(line, 1);
+        let (id, name) = match container.split_once(' ') {
+            Some((id, name)) => (id, name.trim_matches(|c| c == '(' || c == ')')),
+            None => (container.as_str(), ""),
+        };
+
+        response.push(AutoUpdateReponse {
+            unit: field(line, 0),
+            container_id: id.to_owned(),
+            container_name: name.to_owned(),
+            container: container.clone(),
+            image: field(line, 2),
+            policy: serde_json::from_value(field(line, 3).into()).ok()?,
+            updated: serde_json::from_value(field(line, 4).into()).ok()?,
+            reference: None,
+            force_pulled: false,
+            health: None,
+        });
+    }
+
+    Some(response)
+}
+
+/// Whether a container runs an image other than `image_id`.
+fn changed(image_id: &str, container: &ApiContainer) -> Updated {
+    if image_id == container.image_id {
+        Updated::False
+    } else {
+        Updated::Pending
+    }
+}
+
+enum Transport {
+    Unix(Client<UnixConnector>, PathBuf),
+    Tcp(Client<HttpConnector>, String),
+}
+
+/// A minimal client for the parts of the libpod API needed to emulate
+/// `podman auto-update`.
+///
+/// The API has no auto-update endpoint, so the client pulls newer images for
+/// every labelled container and restarts those whose image changed, though
+/// only recreated containers pick up the new image that way. A dry
+/// run pulls nothing, and compares the registry's digest for the tag with
+/// the local image's instead.
+pub struct ApiClient {
+    transport: Transport,
+    /// For looking up digests in registries.
+    registry: reqwest::Client,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ApiContainer {
+    id: String,
+    names: Vec<String>,
+    image: String,
+    #[serde(rename = "ImageID")]
+    image_id: String,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ApiVersion {
+    version: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ApiImage {
+    id: String,
+    #[serde(default)]
+    repo_digests: Vec<String>,
+}
+
+/// The part of `GET /containers/{id}/json` the hook needs.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ApiInspect {
+    /// The id of the image the container runs.
+    image: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PullReport {
+    id: Option<String>,
+    error: Option<String>,
+}
+
+impl ApiClient {
+    /// Accepts `unix:///path/to/podman.sock` or `http://host:port`.
+    pub fn new(url: &str) -> Result<Self, String> {
+        let transport = if let Some(path) = url.strip_prefix("unix://") {
+            Transport::Unix(Client::builder().build(UnixConnector), path.into())
+        } else if url.starts_with("http://") {
+            Transport::Tcp(Client::new(), url.trim_end_matches('/').to_owned())
+        } else {
+            return Err(format!(
+                "unsupported podman url {:?}, expected unix:// or http://",
+                url
+            ));
+        };
+
+        Ok(ApiClient {
+            transport,
+            registry: registry::client(),
+        })
+    }
+
+    async fn request(&self, method: Method, path: &str) -> Result<Vec<u8>, RunError> {
+        let path = format!("/v4.0.0/libpod{}", path);
+        let uri: Uri = match &self.transport {
+            Transport::Unix(_, socket) => hyperlocal::Uri::new(socket, &path).into(),
+            Transport::Tcp(_, base) => format!("{}{}", base, path)
+                .parse()
+                .map_err(|e| RunError::Api(format!("invalid uri: {}", e)))?,
+        };
+
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .map_err(|e| RunError::Api(e.to_string()))?;
+
+        let response = match &self.transport {
+            Transport::Unix(client, _) => client.request(request).await,
+            Transport::Tcp(client, _) => client.request(request).await,
+        }
+        .map_err(|e| RunError::Api(e.to_string()))?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| RunError::Api(e.to_string()))?;
+
+        if !status.is_success() {
+            return Err(RunError::Api(format!(
+                "{} returned {}: {}",
+                path,
+                status,
+                String::from_utf8_lossy(&body)
+            )));
+        }
+
+        Ok(body.to_vec())
+    }
+
+    /// Finds the labelled containers with a newer image. Unless this is a
+    /// dry run, their images are pulled and the containers restarted.
+    async fn auto_update(&self, dry_run: bool) -> Result<Vec<AutoUpdateReponse>, RunError> {
+        let filters = serde_json::json!({ "label": [AUTOUPDATE_LABEL] }).to_string();
+        let query = serde_urlencoded::to_string([("filters", filters)])
+            .map_err(|e| RunError::Api(e.to_string()))?;
+        let body = self
+            .request(Method::GET, &format!("/containers/json?{}", query))
+            .await?;
+        let containers: Vec<ApiContainer> =
+            serde_json::from_slice(&body).map_err(RunError::Parse)?;
+
+        let mut responses = Vec::with_capacity(containers.len());
+        for container in containers {
+            let policy = container
+                .labels
+                .get(AUTOUPDATE_LABEL)
+                .cloned()
+                .unwrap_or_default();
+            let policy = match policy.as_str() {
+                "registry" | "image" => Policy::Registry,
+                "local" => Policy::Local,
+                policy => {
+                    tracing::warn!(
+                        "skipping container {} with unknown policy {:?}",
+                        container.id,
+                        policy
+                    );
+                    continue;
+                }
+            };
+            let (updated, image_id) = match (policy, dry_run) {
+                (Policy::Registry, true) => (self.registry_changed(&container.image).await, None),
+                (Policy::Registry, false) => {
+                    let id = self.pull(&container.image).await?;
+                    (changed(&id, &container), Some(id))
+                }
+                // only the local policy is left
+                _ => {
+                    let id = self.image(&container.image).await?.id;
+                    (changed(&id, &container), Some(id))
+                }
+            };
+
+            let name = container.names.first().cloned().unwrap_or_default();
+            let mut response = AutoUpdateReponse {
+                unit: container
+                    .labels
+                    .get(UNIT_LABEL)
+                    .cloned()
+                    .unwrap_or_default(),
+                container: format!("{} ({})", &container.id[..12.min(container.id.len())], name),
+                image: container.image,
+                container_name: name,
+                container_id: container.id,
+                policy,
+                updated,
+                reference: None,
+                force_pulled: false,
+                health: None,
+            };
+            match image_id {
+                Some(id) if !dry_run && response.updated == Updated::Pending => {
+                    self.restart(&mut response, &id).await;
+                }
+                _ => {}
+            }
+            responses.push(response);
+        }
+
+        Ok(responses)
+    }
+
+    /// Pulls a pending container's image (which is a no-op when the dry run
+    /// already found it locally) and restarts it.
+    async fn update_container(&self, container: &mut AutoUpdateReponse) {
+        let image_id = match container.policy {
+            Policy::Registry => self.pull(&container.image).await,
+            _ => self.image(&container.image).await.map(|image| image.id),
+        };
+        match image_id {
+            Ok(id) => self.restart(container, &id).await,
+            Err(e) => {
+                tracing::error!("{}", e);
+                container.updated = Updated::Failed;
+            }
+        }
+    }
+
+    /// Restarts a container whose image changed to `image_id`. The api can
+    /// only restart containers, which keeps the image they were created
+    /// from, so the container only counts as updated if it came back with
+    /// the new image (because something recreated it). Otherwise it is still
+    /// `pending`.
+    async fn restart(&self, container: &mut AutoUpdateReponse, image_id: &str) {
+        let id = &container.container_id;
+        let restarted = match self
+            .request(Method::POST, &format!("/containers/{}/restart", id))
+            .await
+        {
+            Ok(_) => {
+                self.request(Method::GET, &format!("/containers/{}/json", id))
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        let running: ApiInspect = match restarted
+            .and_then(|body| serde_json::from_slice(&body).map_err(RunError::Parse))
+        {
+            Ok(running) => running,
+            Err(e) => {
+                tracing::error!(
+                    "failed to restart container {}: {}",
+                    container.container_name,
+                    e
+                );
+                container.updated = Updated::Failed;
+                return;
+            }
+        };
+
+        container.updated = if running.image == image_id {
+            tracing::info!("restarted container {}", container.container_name);
+            Updated::True
+        } else {
+            tracing::warn!(
+                "container {} still runs its old image after the restart, it has to be \
+                 recreated to use the new one",
+                container.container_name
+            );
+            Updated::Pending
+        };
+    }
+
+    /// Whether the registry has a different image for the tag than the one
+    /// pulled locally, without pulling it.
+    async fn registry_changed(&self, image: &str) -> Updated {
+        let local = match self.image(image).await {
+            Ok(local) => local,
+            Err(e) => {
+                tracing::warn!("failed to inspect {}: {}", image, e);
+                return Updated::Failed;
+            }
+        };
+        match registry::remote_digest(&self.registry, &ImageReference::parse(image)).await {
+            Ok(remote) if local.repo_digests.iter().any(|d| d.ends_with(&remote)) => Updated::False,
+            Ok(_) => Updated::Pending,
+            Err(e) => {
+                tracing::warn!("failed to look up the digest of {}: {}", image, e);
+                Updated::Failed
+            }
+        }
+    }
+
+    async fn version(&self) -> Result<String, RunError> {
+        let body = self.request(Method::GET, "/version").await?;
+        let version: ApiVersion = serde_json::from_slice(&body).map_err(RunError::Parse)?;
+        Ok(format!("podman version {}", version.version))
+    }
+
+    async fn image(&self, image: &str) -> Result<ApiImage, RunError> {
+        let body = self
+            .request(Method::GET, &format!("/images/{}/json", image))
+            .await?;
+        serde_json::from_slice(&body).map_err(RunError::Parse)
+    }
+
+    async fn pull(&self, image: &str) -> Result<String, RunError> {
+        let query = serde_urlencoded::to_string([
+            ("reference", image),
+            ("policy", "newer"),
+            ("quiet", "true"),
+        ])
+        .map_err(|e| RunError::Api(e.to_string()))?;
+        let body = self
+            .request(Method::POST, &format!("/images/pull?{}", query))
+            .await?;
+
+        let mut id = None;
+        for report in serde_json::Deserializer::from_slice(&body).into_iter::<PullReport>() {
+            let report = report.map_err(RunError::Parse)?;
+            if let Some(error) = report.error {
+                return Err(RunError::Api(format!(
+                    "failed to pull {}: {}",
+                    image, error
+                )));
+            }
+            id = report.id.or(id);
+        }
+
+        id.ok_or_else(|| RunError::Api(format!("pull of {} returned no image id", image)))
+    }
+}
@@ -0,0 +1,43 @@
+//! Finding the image a registry's push webhook is about, and matching it
+//! against the images an endpoint updates for.
+
+use serde_json::Value;
+
+use crate::image::ImageReference;
+
+/// The image a webhook is about: an `image` field from a custom sender,
+/// docker hub's `repository.repo_name` and `push_data.tag`, or the package
+/// url of a github `package` or `registry_package` event (which is how ghcr
+/// reports pushes). Short docker hub names resolve to `docker.io/library`.
+pub fn pushed_image(payload: &Value) -> Option<ImageReference> {
+    let url = [
+        "/image",
+        "/package/package_version/package_url",
+        "/registry_package/package_version/package_url",
+    ]
+    .iter()
+    .find_map(|pointer| payload.pointer(pointer)?.as_str());
+    if let Some(url) = url {
+        return Some(ImageReference::parse(url));
+    }
+
+    let repository = payload.pointer("/repository/repo_name")?.as_str()?;
+    let mut image = ImageReference::parse(repository);
+    if let Some(tag) = payload.pointer("/push_data/tag").and_then(Value::as_str) {
+        image.tag = Some(tag.to_owned());
+    }
+    Some(image)
+}
+
+/// Whether a configured image like `ghcr.io/me/app` matches a pushed one.
+/// An image without a tag matches every tag of its repository, and one with
+/// a tag only that tag.
+pub fn matches(image: &str, pushed: &ImageReference) -> bool {
+    let wanted = ImageReference::parse(image);
+    // a registry port also has a colon, but never in the last component
+    let name = image.split('@').next().unwrap_or(image);
+    let any_tag = !name.rsplit('/').next().unwrap_or(name).contains(':');
+    wanted.registry == pushed.registry
+        && wanted.repository == pushed.repository
+        && (any_tag || wanted.tag == pushed.tag)
+}
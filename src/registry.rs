@@ -0,0 +1,159 @@
+//! A quick check that the registries are up before podman tries to pull from
+//! them, so an outage gets a clear error instead of a failed update, and
+//! looking up the digest a tag points to without pulling it.
+
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use reqwest::{header, StatusCode};
+use serde::Deserialize;
+
+use crate::image::ImageReference;
+
+/// How long a registry may take to answer before it counts as unreachable.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct RegistryProbe {
+    client: reqwest::Client,
+    urls: Vec<String>,
+}
+
+impl RegistryProbe {
+    pub fn new(registries: &[String]) -> Option<Self> {
+        if registries.is_empty() {
+            return None;
+        }
+
+        Some(RegistryProbe {
+            client: client(),
+            urls: registries.iter().map(|r| base_url(r)).collect(),
+        })
+    }
+
+    /// Sends a `HEAD /v2/` to every registry and returns the ones that didn't
+    /// answer. Any status counts as an answer, since most registries want
+    /// authentication before they say more than 401.
+    pub async fn unreachable(&self) -> Vec<&str> {
+        let probes = self.urls.iter().map(|url| async move {
+            match self.client.head(url).send().await {
+                Ok(_) => None,
+                Err(e) => {
+                    tracing::warn!("registry {} is unreachable: {}", url, e);
+                    Some(url.as_str())
+                }
+            }
+        });
+        join_all(probes).await.into_iter().flatten().collect()
+    }
+}
+
+/// A client that gives up on a registry after the [`TIMEOUT`].
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .expect("the tls backend is available")
+}
+
+/// The manifest types a registry may answer with, so it returns the digest
+/// podman records for the image rather than converting it to an older type.
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json, \
+    application/vnd.oci.image.manifest.v1+json";
+
+#[derive(Deserialize)]
+struct Token {
+    #[serde(alias = "access_token")]
+    token: String,
+}
+
+/// The digest the registry has for an image's tag, from a `HEAD` of its
+/// manifest. Registries that want a token for that (like docker hub and
+/// ghcr, even for public images) get an anonymous one from the realm their
+/// challenge names.
+pub async fn remote_digest(
+    client: &reqwest::Client,
+    image: &ImageReference,
+) -> Result<String, String> {
+    if let Some(digest) = &image.digest {
+        return Ok(digest.clone());
+    }
+    let url = format!(
+        "{}{}/manifests/{}",
+        base_url(&image.registry),
+        image.repository,
+        image.tag.as_deref().unwrap_or("latest")
+    );
+    let head = || client.head(&url).header(header::ACCEPT, MANIFEST_TYPES);
+
+    let mut response = head().send().await.map_err(|e| e.to_string())?;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        let challenge = response
+            .headers()
+            .get(header::WWW_AUTHENTICATE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or("the registry wants credentials")?;
+        let token = anonymous_token(client, challenge).await?;
+        response = head()
+            .bearer_auth(token)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    if !response.status().is_success() {
+        return Err(format!("the registry answered {}", response.status()));
+    }
+    response
+        .headers()
+        .get("docker-content-digest")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+        .ok_or_else(|| "the registry sent no digest".to_owned())
+}
+
+/// Fetches a token for a challenge like
+/// `realm="https://ghcr.io/token",service="ghcr.io",scope="repository:me/app:pull"`.
+async fn anonymous_token(client: &reqwest::Client, challenge: &str) -> Result<String, String> {
+    let mut realm = None;
+    let mut query = Vec::new();
+    for param in challenge.split(',') {
+        let Some((key, value)) = param.trim().split_once('=') else {
+            continue;
+        };
+        let value = value.trim_matches('"');
+        match key {
+            "realm" => realm = Some(value),
+            "service" | "scope" => query.push((key, value)),
+            _ => {}
+        }
+    }
+    let realm = realm.ok_or("the registry's challenge has no realm")?;
+    let token: Token = client
+        .get(realm)
+        .query(&query)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(token.token)
+}
+
+/// The `/v2/` url of a registry given as a hostname like `ghcr.io`, or as a
+/// url to probe a plain http registry.
+fn base_url(registry: &str) -> String {
+    let registry = registry.trim_end_matches('/');
+    if registry.contains("://") {
+        return format!("{}/v2/", registry);
+    }
+    // docker.io itself only serves the website
+    let host = match registry {
+        "docker.io" => "registry-1.docker.io",
+        host => host,
+    };
+    format!("https://{}/v2/", host)
+}
@@ -0,0 +1,110 @@
+//! Secrets that are fetched at startup instead of being written out, e.g.
+//! `env://GITHUB_SECRET`, `file:///run/secrets/hook` or
+//! `vault://secret/data/hook#github`.
+
+use std::time::Duration;
+
+use crate::config::ConfigError;
+
+/// How long vault may take to answer, so an unreachable one fails startup
+/// instead of hanging it.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Somewhere a secret can be read from, addressed by the part of the URI
+/// after the scheme.
+trait SecretSource {
+    async fn fetch(&self, reference: &str) -> Result<String, String>;
+}
+
+/// `env://NAME` reads an environment variable.
+struct Env;
+
+impl SecretSource for Env {
+    async fn fetch(&self, reference: &str) -> Result<String, String> {
+        std::env::var(reference).map_err(|_| format!("{} is not set", reference))
+    }
+}
+
+/// `file:///path` reads a file, without its trailing newline.
+struct File;
+
+impl SecretSource for File {
+    async fn fetch(&self, reference: &str) -> Result<String, String> {
+        let contents = tokio::fs::read_to_string(reference)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(contents.trim_end().to_owned())
+    }
+}
+
+/// `vault://<path>#<field>` reads a field of a vault secret, using the
+/// standard `VAULT_ADDR` and `VAULT_TOKEN` variables. Both the KV v1 and v2
+/// response layouts are understood.
+struct Vault {
+    addr: String,
+    token: String,
+}
+
+impl Vault {
+    fn from_env() -> Result<Self, String> {
+        let var = |name| std::env::var(name).map_err(|_| format!("{} is not set", name));
+        Ok(Vault {
+            addr: var("VAULT_ADDR")?,
+            token: var("VAULT_TOKEN")?,
+        })
+    }
+}
+
+impl SecretSource for Vault {
+    async fn fetch(&self, reference: &str) -> Result<String, String> {
+        let (path, field) = reference
+            .split_once('#')
+            .ok_or("expected vault://<path>#<field>")?;
+        let url = format!("{}/v1/{}", self.addr.trim_end_matches('/'), path);
+
+        let response: serde_json::Value = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .expect("the tls backend is available")
+            .get(&url)
+            .header("X-Vault-Token", &self.token)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let data = &response["data"];
+        data["data"][field]
+            .as_str()
+            .or_else(|| data[field].as_str())
+            .map(str::to_owned)
+            .ok_or_else(|| format!("{} has no string field {:?}", path, field))
+    }
+}
+
+/// Fetches `value` if it is a secret URI, and otherwise returns it as is.
+pub async fn resolve(value: &str) -> Result<String, ConfigError> {
+    let Some((scheme, reference)) = value.split_once("://") else {
+        return Ok(value.to_owned());
+    };
+
+    let secret = match scheme {
+        "env" => Env.fetch(reference).await,
+        "file" => File.fetch(reference).await,
+        "vault" => match Vault::from_env() {
+            Ok(vault) => vault.fetch(reference).await,
+            Err(e) => Err(e),
+        },
+        _ => return Ok(value.to_owned()),
+    };
+
+    secret.map_err(|e| {
+        ConfigError::Invalid(format!(
+            "failed to fetch secret {}://{}: {}",
+            scheme, reference, e
+        ))
+    })
+}
@@ -0,0 +1,46 @@
+//! Checking the `X-Hub-Signature-256` of a github delivery against every
+//! secret that could have signed it.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+/// Computes the HMAC-SHA256 of a body as it streams in, once per candidate
+/// secret, the way github signs deliveries.
+pub struct Verifier {
+    macs: Vec<Hmac<Sha256>>,
+}
+
+impl Verifier {
+    pub fn new(secrets: impl IntoIterator<Item = impl AsRef<[u8]>>) -> Self {
+        let macs = secrets
+            .into_iter()
+            .map(|secret| {
+                Hmac::new_from_slice(secret.as_ref()).expect("hmac accepts keys of any size")
+            })
+            .collect();
+        Verifier { macs }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        for mac in &mut self.macs {
+            mac.update(chunk);
+        }
+    }
+
+    /// Returns the index of the secret whose signature is `expected`, the hex
+    /// part of the header. The raw digests are compared in constant time, and
+    /// every secret is checked so the timing doesn't reveal which one matched.
+    /// A signature that isn't hex, or has the wrong length, matches none.
+    pub fn verify(self, expected: &str) -> Option<usize> {
+        let expected = hex::decode(expected).ok()?;
+        self.macs
+            .into_iter()
+            .map(|mac| mac.finalize().into_bytes())
+            .enumerate()
+            .fold(None, |matched, (i, signature)| {
+                let eq: bool = signature.as_slice().ct_eq(&expected).into();
+                matched.or(eq.then_some(i))
+            })
+    }
+}
@@ -0,0 +1,147 @@
+//! Serving the hook over https with rustls, for when there is no reverse
+//! proxy in front of it to terminate TLS.
+
+use std::{path::Path, sync::Arc};
+
+use clap::ValueEnum;
+use rustls::{
+    version::{TLS12, TLS13},
+    Certificate, PrivateKey, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion,
+    ALL_CIPHER_SUITES,
+};
+use rustls_pemfile::Item;
+
+use crate::{config::ConfigError, Opt};
+
+/// The oldest TLS version a client may connect with.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum)]
+pub enum TlsVersion {
+    #[value(name = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn name(self) -> &'static str {
+        match self {
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        }
+    }
+
+    fn versions(self) -> &'static [&'static SupportedProtocolVersion] {
+        static TLS12_UP: &[&SupportedProtocolVersion] = &[&TLS13, &TLS12];
+        static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&TLS13];
+        match self {
+            TlsVersion::Tls12 => TLS12_UP,
+            TlsVersion::Tls13 => TLS13_ONLY,
+        }
+    }
+}
+
+/// The rustls config for `--tls-cert` and `--tls-key`, or `None` to serve
+/// plain http. Clients that can't agree on a version and cipher suite in
+/// the policy fail the handshake.
+pub fn server_config(opt: &Opt) -> Result<Option<Arc<ServerConfig>>, ConfigError> {
+    let (Some(cert), Some(key)) = (&opt.tls_cert, &opt.tls_key) else {
+        return Ok(None);
+    };
+
+    let versions = opt.tls_min_version.versions();
+    let suites = cipher_suites(&opt.tls_cipher_suites)?;
+    if !suites.iter().any(|s| versions.contains(&s.version())) {
+        return Err(ConfigError::Invalid(format!(
+            "none of the cipher suites can be used with TLS {}+",
+            opt.tls_min_version.name()
+        )));
+    }
+
+    let mut config = ServerConfig::builder()
+        .with_cipher_suites(&suites)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .map_err(|e| ConfigError::Invalid(format!("invalid tls policy: {}", e)))?
+        .with_no_client_auth()
+        .with_single_cert(certificates(cert)?, private_key(key)?)
+        .map_err(|e| ConfigError::Invalid(format!("invalid tls certificate: {}", e)))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    let names: Vec<_> = suites
+        .iter()
+        .filter(|s| versions.contains(&s.version()))
+        .map(|s| format!("{:?}", s.suite()))
+        .collect();
+    tracing::info!(
+        "serving https with TLS {}+ and the cipher suites {}",
+        opt.tls_min_version.name(),
+        names.join(", ")
+    );
+    Ok(Some(Arc::new(config)))
+}
+
+/// The suites named by `--tls-cipher-suite`, or every suite rustls supports
+/// (all of them have forward secrecy and an AEAD cipher) without any.
+fn cipher_suites(names: &[String]) -> Result<Vec<SupportedCipherSuite>, ConfigError> {
+    if names.is_empty() {
+        return Ok(ALL_CIPHER_SUITES.to_vec());
+    }
+    names
+        .iter()
+        .map(|name| {
+            ALL_CIPHER_SUITES
+                .iter()
+                .find(|s| format!("{:?}", s.suite()).eq_ignore_ascii_case(name))
+                .copied()
+                .ok_or_else(|| {
+                    ConfigError::Invalid(format!("unsupported tls cipher suite {}", name))
+                })
+        })
+        .collect()
+}
+
+fn read_pem(path: &Path, what: &str) -> Result<Vec<Item>, ConfigError> {
+    let pem = std::fs::read(path).map_err(|e| {
+        ConfigError::Invalid(format!(
+            "failed to read tls {} {}: {}",
+            what,
+            path.display(),
+            e
+        ))
+    })?;
+    rustls_pemfile::read_all(&mut &pem[..]).map_err(|e| {
+        ConfigError::Invalid(format!(
+            "failed to parse tls {} {}: {}",
+            what,
+            path.display(),
+            e
+        ))
+    })
+}
+
+fn certificates(path: &Path) -> Result<Vec<Certificate>, ConfigError> {
+    let certs: Vec<_> = read_pem(path, "certificate")?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(ConfigError::Invalid(format!(
+            "no certificates in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> Result<PrivateKey, ConfigError> {
+    read_pem(path, "key")?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| ConfigError::Invalid(format!("no private key in {}", path.display())))
+}
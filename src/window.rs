@@ -0,0 +1,68 @@
+//! A daily maintenance window like `02:00-05:00`, in the host's local time.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+const DAY: u32 = 24 * 60 * 60;
+
+/// The hours when updates may run, as seconds since local midnight. A window
+/// whose end is before its start wraps around midnight.
+#[derive(Debug, Clone, Copy)]
+pub struct Window {
+    start: u32,
+    end: u32,
+}
+
+impl Window {
+    /// How long until the window opens, or `None` if it is open now.
+    pub fn until_open(&self) -> Option<Duration> {
+        let now = seconds_since_midnight();
+        let open = match self.start <= self.end {
+            true => (self.start..self.end).contains(&now),
+            false => now >= self.start || now < self.end,
+        };
+        (!open).then(|| Duration::from_secs(u64::from((self.start + DAY - now) % DAY)))
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let time = |s: &str| {
+            let (hours, minutes) = s.split_once(':')?;
+            let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+            (hours < 24 && minutes < 60).then_some(hours * 3600 + minutes * 60)
+        };
+        let (start, end) = s
+            .split_once('-')
+            .and_then(|(start, end)| Some((time(start)?, time(end)?)))
+            .ok_or_else(|| format!("expected HH:MM-HH:MM, got {:?}", s))?;
+        if start == end {
+            return Err("the window must not be empty".to_owned());
+        }
+        Ok(Window { start, end })
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hm = |s: u32| format!("{:02}:{:02}", s / 3600, s / 60 % 60);
+        write!(f, "{}-{}", hm(self.start), hm(self.end))
+    }
+}
+
+/// The local time of day, following `TZ` like the rest of the system.
+fn seconds_since_midnight() -> u32 {
+    // SAFETY: time with a null pointer only returns the time, and
+    // localtime_r only writes to the tm it is given
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return 0;
+        }
+        tm
+    };
+    // a leap second is counted as the last second of the minute
+    (tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec.min(59)) as u32
+}
@@ -0,0 +1,915 @@
+//! Tests that drive the router directly, without binding a socket.
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{Request, StatusCode},
+    Router,
+};
+use clap::Parser;
+use hmac::{Hmac, Mac};
+use podman_autoupdate_hook::{build_app, config::Config, Opt};
+use sha2::Sha256;
+use std::{net::SocketAddr, os::unix::fs::PermissionsExt};
+use tempfile::TempDir;
+use tower::ServiceExt;
+
+const BODY: &str = r#"{"zen":"Keep it logically awesome."}"#;
+
+/// Parses the options with a fake podman that reports no containers.
+fn opt(args: &[&str]) -> (Opt, TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let podman = dir.path().join("podman");
+    std::fs::write(&podman, "#!/bin/sh\necho '[]'\n").unwrap();
+    std::fs::set_permissions(&podman, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let opt = Opt::parse_from(
+        ["podman-autoupdate-hook", "--podman-binary"]
+            .into_iter()
+            .chain(podman.to_str())
+            .chain(args.iter().copied()),
+    );
+    (opt, dir)
+}
+
+/// Builds the app, allowing hooks without authentication.
+fn app(args: &[&str]) -> (Router, TempDir) {
+    let args: Vec<_> = std::iter::once("--insecure-no-auth")
+        .chain(args.iter().copied())
+        .collect();
+    let (opt, dir) = opt(&args);
+    (build_app(opt, Config::default()).unwrap().router, dir)
+}
+
+async fn post(app: Router, headers: &[(&str, &str)]) -> StatusCode {
+    post_body(app, headers, BODY.to_owned()).await
+}
+
+async fn post_body(app: Router, headers: &[(&str, &str)], body: String) -> StatusCode {
+    let mut request = Request::post("/hook");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let mut request = request.body(Body::from(body)).unwrap();
+    // the rate limiter keys anonymous requests on the peer address
+    request
+        .extensions_mut()
+        .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+
+    app.oneshot(request).await.unwrap().status()
+}
+
+fn github_signature(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[tokio::test]
+async fn unauthenticated_hook_accepts_anything() {
+    let (app, _dir) = app(&[]);
+    assert_eq!(post(app, &[]).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn hooks_need_authentication_unless_allowed() {
+    let (no_auth, _dir) = opt(&[]);
+    assert!(build_app(no_auth, Config::default()).is_err());
+
+    let (token, _dir) = opt(&["token", "secret"]);
+    assert!(build_app(token, Config::default()).is_ok());
+
+    let (endpoint_without_auth, _dir) = opt(&["token", "secret"]);
+    let config: Config = toml::from_str("[[endpoints]]\nname = \"open\"\n").unwrap();
+    assert!(build_app(endpoint_without_auth, config).is_err());
+}
+
+#[tokio::test]
+async fn bearer_token() {
+    for (headers, status) in [
+        (&[][..], StatusCode::UNAUTHORIZED),
+        (
+            &[("Authorization", "Bearer wrong")],
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            &[("Authorization", "Bearer secre")],
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            &[("Authorization", "Bearer secrets")],
+            StatusCode::UNAUTHORIZED,
+        ),
+        (&[("Authorization", "Bearer secret")], StatusCode::OK),
+    ] {
+        let (app, _dir) = app(&["token", "secret"]);
+        assert_eq!(post(app, headers).await, status, "{:?}", headers);
+    }
+}
+
+#[tokio::test]
+async fn github_signature_matrix() {
+    let valid = github_signature("secret", BODY);
+    let wrong = github_signature("wrong", BODY);
+    for (headers, status) in [
+        (vec![], StatusCode::BAD_REQUEST),
+        (
+            vec![("X-Hub-Signature-256", "not-a-signature")],
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            vec![("X-Hub-Signature-256", wrong.as_str())],
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            vec![("X-Hub-Signature-256", valid.as_str())],
+            StatusCode::OK,
+        ),
+        (
+            vec![
+                ("Authorization", "Bearer secret"),
+                ("X-Hub-Signature-256", wrong.as_str()),
+            ],
+            StatusCode::UNAUTHORIZED,
+        ),
+    ] {
+        let (app, _dir) = app(&["github", "secret"]);
+        assert_eq!(post(app, &headers).await, status, "{:?}", headers);
+    }
+}
+
+#[tokio::test]
+async fn dashboard_is_only_served_locally() {
+    for (peer, status) in [
+        ([127, 0, 0, 1], StatusCode::OK),
+        ([192, 0, 2, 1], StatusCode::FORBIDDEN),
+    ] {
+        let (app, _dir) = app(&["--dashboard"]);
+        let mut request = Request::get("/").body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((peer, 1234))));
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), status, "{:?}", peer);
+    }
+}
+
+#[tokio::test]
+async fn metrics_are_served_to_allowed_ranges() {
+    for (peer, status) in [
+        ([127, 0, 0, 1], StatusCode::OK),
+        ([10, 1, 2, 3], StatusCode::OK),
+        ([192, 0, 2, 1], StatusCode::FORBIDDEN),
+    ] {
+        let (app, _dir) = app(&["--metrics", "--metrics-allow", "10.0.0.0/8"]);
+        let mut request = Request::get("/metrics").body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((peer, 1234))));
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), status, "{:?}", peer);
+    }
+}
+
+#[tokio::test]
+async fn form_payloads_are_unwrapped() {
+    let form = serde_urlencoded::to_string([("payload", BODY)]).unwrap();
+    for (content_type, body, status) in [
+        ("application/json", BODY.to_owned(), StatusCode::OK),
+        (
+            "application/x-www-form-urlencoded",
+            form.clone(),
+            StatusCode::OK,
+        ),
+        (
+            "application/x-www-form-urlencoded",
+            BODY.to_owned(),
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            "text/plain",
+            BODY.to_owned(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        ),
+    ] {
+        let (app, _dir) = app(&["--require-json-field", "zen"]);
+        let headers = [("Content-Type", content_type)];
+        assert_eq!(
+            post_body(app, &headers, body).await,
+            status,
+            "{}",
+            content_type
+        );
+    }
+}
+
+#[tokio::test]
+async fn status_is_marked_stale() {
+    for (args, status, stale) in [
+        (&["--status"][..], StatusCode::OK, false),
+        (
+            &["--status", "--status-stale-after", "0s"],
+            StatusCode::OK,
+            true,
+        ),
+        (
+            &[
+                "--status",
+                "--status-stale-after",
+                "0s",
+                "--status-stale-code",
+                "503",
+            ],
+            StatusCode::SERVICE_UNAVAILABLE,
+            true,
+        ),
+    ] {
+        let (app, _dir) = app(args);
+        let mut request = Request::get("/status").body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), status, "{:?}", args);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["stale"], stale, "{:?}", args);
+        assert_eq!(body["age_seconds"], serde_json::Value::Null);
+    }
+}
+
+#[tokio::test]
+async fn github_hook_id_must_match() {
+    let signature = github_signature("secret", BODY);
+    for (hook_id, status) in [
+        (None, StatusCode::NO_CONTENT),
+        (Some("456"), StatusCode::NO_CONTENT),
+        (Some("123"), StatusCode::OK),
+    ] {
+        let (app, _dir) = app(&[
+            "--skip-status",
+            "204",
+            "github",
+            "secret",
+            "--github-hook-id",
+            "123",
+        ]);
+        let mut headers = vec![("X-Hub-Signature-256", signature.as_str())];
+        headers.extend(hook_id.map(|id| ("X-GitHub-Hook-ID", id)));
+        assert_eq!(post(app, &headers).await, status, "{:?}", hook_id);
+    }
+}
+
+#[tokio::test]
+async fn events_are_dispatched_to_their_action() {
+    let config = r#"
+        [actions]
+        package = "auto-update"
+        release = "notify"
+        "*" = "skip"
+    "#;
+    for (event, status) in [
+        (Some("package"), StatusCode::OK),
+        (Some("release"), StatusCode::ACCEPTED),
+        (Some("push"), StatusCode::NO_CONTENT),
+        (None, StatusCode::NO_CONTENT),
+    ] {
+        let (opt, _dir) = opt(&[
+            "--insecure-no-auth",
+            "--skip-status",
+            "204",
+            // nothing listens here, notifications are best effort
+            "--ntfy-url",
+            "http://127.0.0.1:9/topic",
+        ]);
+        let app = build_app(opt, toml::from_str(config).unwrap())
+            .unwrap()
+            .router;
+        let headers: Vec<_> = event.map(|e| ("X-GitHub-Event", e)).into_iter().collect();
+        assert_eq!(post(app, &headers).await, status, "{:?}", event);
+    }
+
+    let (opt, _dir) = opt(&["--insecure-no-auth"]);
+    assert!(build_app(opt, toml::from_str(config).unwrap()).is_err());
+}
+
+#[tokio::test]
+async fn user_agent_must_contain_the_required_substring() {
+    for (user_agent, status) in [
+        (Some("GitHub-Hookshot/044aadd"), StatusCode::OK),
+        (Some("zgrab/0.x"), StatusCode::FORBIDDEN),
+        (None, StatusCode::FORBIDDEN),
+    ] {
+        let (app, _dir) = app(&["--require-user-agent", "GitHub-Hookshot/"]);
+        let headers: Vec<_> = user_agent
+            .map(|ua| ("User-Agent", ua))
+            .into_iter()
+            .collect();
+        assert_eq!(post(app, &headers).await, status, "{:?}", user_agent);
+    }
+}
+
+#[tokio::test]
+async fn updates_outside_the_maintenance_window_are_deferred() {
+    // SAFETY: time with a null pointer only returns the time, and
+    // localtime_r only writes to the tm it is given
+    let hour = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        tm.tm_hour
+    };
+    let window = |from: i32| {
+        format!(
+            "{:02}:00-{:02}:00",
+            (hour + from) % 24,
+            (hour + from + 1) % 24
+        )
+    };
+
+    let (open, _dir) = app(&["--maintenance-window", &window(0)]);
+    assert_eq!(post(open, &[]).await, StatusCode::OK);
+
+    let (closed, _dir) = app(&["--maintenance-window", &window(2)]);
+    assert_eq!(post(closed, &[]).await, StatusCode::ACCEPTED);
+}
+
+#[tokio::test]
+async fn hook_and_base_paths_are_normalized() {
+    for (path, status) in [
+        ("/podman/hooks/main", StatusCode::OK),
+        ("/podman/health", StatusCode::OK),
+        ("/hook", StatusCode::NOT_FOUND),
+    ] {
+        let (app, _dir) = app(&["--base-path", "podman/", "--hook-path", "//hooks/main/"]);
+        let mut request = Request::get(path).body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), status, "{}", path);
+    }
+
+    for path in ["/hook?x=1", "/my hook", "/"] {
+        let (opt, _dir) = opt(&["--insecure-no-auth", "--hook-path", path]);
+        assert!(build_app(opt, Config::default()).is_err(), "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn docker_refuses_container_policies() {
+    for policy in ["ignore", "dry-run-only"] {
+        let (opt, _dir) = opt(&["--insecure-no-auth", "--runtime", "docker"]);
+        let config: Config =
+            toml::from_str(&format!("[containers]\nweb = \"{}\"\n", policy)).unwrap();
+        assert!(build_app(opt, config).is_err(), "{}", policy);
+    }
+
+    let (opt, _dir) = opt(&["--insecure-no-auth", "--runtime", "docker"]);
+    let config: Config = toml::from_str("[containers]\nweb = \"immediate\"\n").unwrap();
+    assert!(build_app(opt, config).is_ok());
+}
+
+#[tokio::test]
+async fn filters_need_the_podman_cli() {
+    for filter in [
+        ["--filter-label", "deploy.group=web"],
+        ["--allow-unit", "web.service"],
+    ] {
+        for runner in [
+            ["--runtime", "docker"],
+            ["--podman-url", "http://127.0.0.1:1"],
+        ] {
+            let mut args = vec!["--insecure-no-auth"];
+            args.extend(filter);
+            args.extend(runner);
+            let (opt, _dir) = opt(&args);
+            assert!(build_app(opt, Config::default()).is_err(), "{:?}", args);
+        }
+    }
+}
+
+#[tokio::test]
+async fn hooks_cannot_take_reserved_routes() {
+    for path in ["/admin/drain", "/admin/resume", "/metrics"] {
+        let (opt, _dir) = opt(&[
+            "--insecure-no-auth",
+            "--admin-token",
+            "x",
+            "--metrics",
+            "--hook-path",
+            path,
+        ]);
+        assert!(build_app(opt, Config::default()).is_err(), "{}", path);
+    }
+
+    let (opt, _dir) = opt(&["--insecure-no-auth", "--hook-path", "/admin"]);
+    let config: Config = toml::from_str("[[endpoints]]\nname = \"drain\"\n").unwrap();
+    assert!(build_app(opt, config).is_err());
+}
+
+#[tokio::test]
+async fn unreachable_registries_fail_the_update() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // a registry that wants authentication, like most do
+    let registry = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let up = format!("http://{}", registry.local_addr().unwrap());
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = registry.accept().await {
+            let _ = stream.read(&mut [0; 1024]).await;
+            let _ = stream
+                .write_all(b"HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\n\r\n")
+                .await;
+        }
+    });
+    let down = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+
+    let (reachable, _dir) = app(&["--probe-registry", &up]);
+    assert_eq!(post(reachable, &[]).await, StatusCode::OK);
+
+    let (unreachable, _dir) = app(&["--probe-registry", &up, "--probe-registry", &down]);
+    assert_eq!(post(unreachable, &[]).await, StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn unsupported_accept_types_are_refused() {
+    for (accept, status) in [
+        ("application/xml", StatusCode::NOT_ACCEPTABLE),
+        (
+            "application/json;q=0, text/html",
+            StatusCode::NOT_ACCEPTABLE,
+        ),
+        ("application/xml, */*;q=0.1", StatusCode::OK),
+    ] {
+        let (app, _dir) = app(&[]);
+        assert_eq!(post(app, &[("Accept", accept)]).await, status, "{}", accept);
+    }
+}
+
+#[tokio::test]
+async fn form_encoded_github_deliveries_are_verified_and_unwrapped() {
+    let payload = |repo: &str| {
+        let json = format!(r#"{{"repository":{{"full_name":"{}"}}}}"#, repo);
+        (
+            serde_urlencoded::to_string([("payload", &json)]).unwrap(),
+            json,
+        )
+    };
+    let (ours, ours_json) = payload("my-org/app");
+    let (theirs, _) = payload("other-org/app");
+
+    for (body, signed, status) in [
+        (&ours, &ours, StatusCode::OK),
+        (&theirs, &theirs, StatusCode::NO_CONTENT),
+        // the signature covers the whole form, not just the payload in it
+        (&ours, &ours_json, StatusCode::UNAUTHORIZED),
+    ] {
+        let (app, _dir) = app(&[
+            "--skip-status",
+            "204",
+            "github",
+            "secret",
+            "--github-repo",
+            "my-org/app",
+        ]);
+        let signature = github_signature("secret", signed);
+        let headers = [
+            ("Content-Type", "application/x-www-form-urlencoded"),
+            ("X-Hub-Signature-256", signature.as_str()),
+        ];
+        assert_eq!(
+            post_body(app, &headers, body.clone()).await,
+            status,
+            "{}",
+            signed
+        );
+    }
+}
+
+#[tokio::test]
+async fn recently_updated_images_are_skipped() {
+    let (router, _dir) = app(&["--skip-status", "204", "--per-image-cooldown", "1h"]);
+    let package =
+        r#"{"package":{"package_version":{"package_url":"docker.io/library/nginx:latest"}}}"#;
+
+    for (body, status) in [
+        (r#"{"image":"nginx"}"#, StatusCode::OK),
+        // the same image, named the way github does
+        (package, StatusCode::NO_CONTENT),
+        (r#"{"image":"ghcr.io/my-org/app"}"#, StatusCode::OK),
+        (BODY, StatusCode::OK),
+    ] {
+        assert_eq!(
+            post_body(router.clone(), &[], body.to_owned()).await,
+            status,
+            "{}",
+            body
+        );
+    }
+}
+
+#[tokio::test]
+async fn github_signature_can_come_from_another_header() {
+    let valid = github_signature("secret", BODY);
+    for (headers, status) in [
+        (
+            vec![("X-Original-Signature", valid.as_str())],
+            StatusCode::OK,
+        ),
+        // only the configured header is looked at
+        (
+            vec![("X-Hub-Signature-256", valid.as_str())],
+            StatusCode::BAD_REQUEST,
+        ),
+    ] {
+        let (router, _dir) = app(&[
+            "github",
+            "secret",
+            "--signature-header",
+            "X-Original-Signature",
+        ]);
+        assert_eq!(post(router, &headers).await, status, "{:?}", headers);
+    }
+
+    let (opt, _dir) = opt(&[
+        "--insecure-no-auth",
+        "github",
+        "secret",
+        "--signature-header",
+        "not a header",
+    ]);
+    assert!(build_app(opt, Config::default()).is_err());
+}
+
+#[tokio::test]
+async fn a_body_that_breaks_off_is_a_bad_request() {
+    let signature = github_signature("secret", BODY);
+    let (router, _dir) = app(&["github", "secret"]);
+
+    let chunks: Vec<Result<_, std::io::Error>> = vec![
+        Ok(&BODY.as_bytes()[..10]),
+        Err(std::io::ErrorKind::ConnectionReset.into()),
+    ];
+    let mut request = Request::post("/hook")
+        .header("X-Hub-Signature-256", signature)
+        .body(Body::wrap_stream(futures_util::stream::iter(chunks)))
+        .unwrap();
+    request
+        .extensions_mut()
+        .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+
+    let response = router.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn large_bodies_are_rejected() {
+    let large = "x".repeat(BODY.len() + 1);
+    for (args, headers, body, status) in [
+        (
+            &["github", "secret"][..],
+            vec![("X-Hub-Signature-256", "sha256=00".to_owned())],
+            BODY.to_owned(),
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            &["github", "secret"],
+            vec![("X-Hub-Signature-256", "sha256=00".to_owned())],
+            large.clone(),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ),
+        // a bearer token endpoint never reads the body, but still checks its length
+        (
+            &["token", "secret"],
+            vec![("Content-Length", large.len().to_string())],
+            large,
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ),
+    ] {
+        let max = BODY.len().to_string();
+        let args: Vec<_> = ["--max-body-bytes", &max]
+            .iter()
+            .chain(args)
+            .copied()
+            .collect();
+        let (router, _dir) = app(&args);
+        let headers: Vec<_> = headers.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        assert_eq!(
+            post_body(router, &headers, body).await,
+            status,
+            "{:?}",
+            args
+        );
+    }
+
+    // an endless chunked body is cut off instead of hashed (or ignored) forever
+    let signature = github_signature("secret", BODY);
+    for (args, header) in [
+        (
+            &["github", "secret"][..],
+            ("X-Hub-Signature-256", signature.as_str()),
+        ),
+        (&["token", "secret"][..], ("Authorization", "Bearer secret")),
+        (&[][..], ("X-Unused", "")),
+    ] {
+        let (router, _dir) = app(args);
+        let chunks =
+            futures_util::stream::repeat_with(|| Ok::<_, std::io::Error>(&[0u8; 4096][..]));
+        let mut request = Request::post("/hook")
+            .header(header.0, header.1)
+            .body(Body::wrap_stream(chunks))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "{:?}",
+            args
+        );
+    }
+}
+
+#[tokio::test]
+async fn github_signatures_are_hmacs() {
+    // the example from github's "validating webhook deliveries" docs
+    let (router, _dir) = app(&["github", "It's a Secret to Everybody"]);
+    let headers = [(
+        "X-Hub-Signature-256",
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17",
+    )];
+    assert_eq!(
+        post_body(router, &headers, "Hello, World!".to_owned()).await,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn registry_pushes_only_update_their_images() {
+    // trimmed from the docker hub and github `package` event documentation
+    let docker_hub = |tag: &str| {
+        format!(
+            r#"{{"push_data":{{"pushed_at":1417566161,"pusher":"trustedbuilder","tag":"{}"}},"repository":{{"name":"testhook","namespace":"svendowideit","repo_name":"svendowideit/testhook"}}}}"#,
+            tag
+        )
+    };
+    let ghcr = |tag: &str| {
+        format!(
+            r#"{{"action":"published","package":{{"name":"app","namespace":"my-org","package_type":"CONTAINER","package_version":{{"container_metadata":{{"tag":{{"name":"{0}"}}}},"package_url":"ghcr.io/my-org/app:{0}"}}}}}}"#,
+            tag
+        )
+    };
+
+    let args = [
+        "--skip-status",
+        "204",
+        "registry",
+        "secret",
+        "svendowideit/testhook",
+        "ghcr.io/my-org/app:v1.2",
+    ];
+    let auth = [("Authorization", "Bearer secret")];
+    for (headers, body, status) in [
+        (&[][..], docker_hub("latest"), StatusCode::UNAUTHORIZED),
+        (
+            &[("Authorization", "Bearer wrong")],
+            docker_hub("latest"),
+            StatusCode::UNAUTHORIZED,
+        ),
+        // any tag of an image without one
+        (&auth, docker_hub("latest"), StatusCode::OK),
+        (&auth, docker_hub("v2"), StatusCode::OK),
+        // only the tag of an image with one
+        (&auth, ghcr("v1.2"), StatusCode::OK),
+        (&auth, ghcr("v1.3"), StatusCode::NO_CONTENT),
+        (
+            &auth,
+            docker_hub("latest").replace("svendowideit/testhook", "svendowideit/other"),
+            StatusCode::NO_CONTENT,
+        ),
+        (&auth, BODY.to_owned(), StatusCode::NO_CONTENT),
+        (&auth, "not json".to_owned(), StatusCode::BAD_REQUEST),
+    ] {
+        let (router, _dir) = app(&args);
+        assert_eq!(
+            post_body(router, headers, body.clone()).await,
+            status,
+            "{:?} {}",
+            headers,
+            body
+        );
+    }
+
+    // docker hub can only put the token in the url
+    for (uri, status) in [
+        ("/hook?token=secret", StatusCode::OK),
+        ("/hook?token=wrong", StatusCode::UNAUTHORIZED),
+    ] {
+        let mut request = Request::post(uri)
+            .body(Body::from(docker_hub("latest")))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+        let (router, _dir) = app(&args);
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), status, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn rejections_say_why() {
+    let signature = github_signature("secret", BODY);
+    for (headers, status, expected) in [
+        (
+            &[][..],
+            StatusCode::BAD_REQUEST,
+            r#"{"error":"missing signature"}"#,
+        ),
+        (
+            &[("X-Hub-Signature-256", "sha256=00")],
+            StatusCode::UNAUTHORIZED,
+            r#"{"error":"signature mismatch"}"#,
+        ),
+        (
+            &[
+                ("X-Hub-Signature-256", signature.as_str()),
+                ("X-GitHub-Event", "issues"),
+            ],
+            StatusCode::OK,
+            r#"{"status":"skipped"}"#,
+        ),
+    ] {
+        let (router, _dir) = app(&["github", "secret", "push"]);
+        let mut request = Request::post("/hook");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let mut request = request.body(Body::from(BODY)).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), status, "{:?}", headers);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, expected, "{:?}", headers);
+    }
+}
+
+#[tokio::test]
+async fn rate_limit_can_be_tuned_or_turned_off() {
+    let get = |router: Router| async move {
+        let mut request = Request::get("/hook").body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+        router.oneshot(request).await.unwrap().status()
+    };
+
+    let (router, _dir) = app(&["--rate-limit-burst", "1"]);
+    assert_eq!(get(router.clone()).await, StatusCode::OK);
+    assert_eq!(get(router).await, StatusCode::TOO_MANY_REQUESTS);
+
+    let (router, _dir) = app(&["--no-rate-limit"]);
+    for _ in 0..20 {
+        assert_eq!(get(router.clone()).await, StatusCode::OK);
+    }
+
+    // zero would panic in the rate limiter, so it is refused up front
+    let (opt, _dir) = opt(&["--insecure-no-auth", "--rate-limit-period", "0s"]);
+    assert!(build_app(opt, Config::default()).is_err());
+    assert!(Opt::try_parse_from(["podman-autoupdate-hook", "--rate-limit-burst", "0"]).is_err());
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn tls_options_need_a_certificate() {
+    let parse = |args: &[&str]| {
+        Opt::try_parse_from(["podman-autoupdate-hook"].iter().chain(args))
+            .map(|_| ())
+            .map_err(|e| e.kind())
+    };
+    assert_eq!(parse(&[]), Ok(()));
+    assert_eq!(
+        parse(&["--tls-min-version", "1.3"]),
+        Err(clap::error::ErrorKind::MissingRequiredArgument)
+    );
+    assert_eq!(
+        parse(&[
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+            "--tls-min-version",
+            "1.3"
+        ]),
+        Ok(())
+    );
+}
+
+#[tokio::test]
+async fn query_tokens_are_rate_limited_by_token() {
+    let get = |router: Router, path: &'static str| async move {
+        let mut request = Request::get(path).body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+        router.oneshot(request).await.unwrap().status()
+    };
+
+    // from the same address, but the configured token gets its own limit
+    let (router, _dir) = app(&["--rate-limit-burst", "1", "registry", "secret"]);
+    assert_eq!(
+        get(router.clone(), "/hook?token=secret").await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        get(router.clone(), "/hook?token=secret").await,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(get(router, "/hook?token=made-up").await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn made_up_tokens_share_the_address_limit() {
+    let get = |router: Router, token: String| async move {
+        let mut request = Request::get("/hook")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+        router.oneshot(request).await.unwrap().status()
+    };
+
+    let (router, _dir) = app(&["--rate-limit-burst", "2", "token", "secret"]);
+    assert_eq!(get(router.clone(), "guess-1".into()).await, StatusCode::OK);
+    assert_eq!(get(router.clone(), "guess-2".into()).await, StatusCode::OK);
+    assert_eq!(
+        get(router.clone(), "guess-3".into()).await,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(get(router, "secret".into()).await, StatusCode::OK);
+}
+
+/// Posts to the hook and returns the status, headers and the JSON `error`.
+async fn post_error(
+    router: Router,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> (StatusCode, axum::http::HeaderMap, String) {
+    let mut request = Request::post(path);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let mut request = request.body(Body::from(BODY)).unwrap();
+    request
+        .extensions_mut()
+        .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+
+    let response = router.oneshot(request).await.unwrap();
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap();
+    // successes like the drain have no body
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+    let error = body["error"].as_str().unwrap_or_default().to_owned();
+    (parts.status, parts.headers, error)
+}
+
+#[tokio::test]
+async fn failed_updates_say_why() {
+    let (router, _dir) = app(&["--fail-on-no-update"]);
+    let (status, _, error) = post_error(router, "/hook", &[]).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error, "no containers were updated");
+
+    let down = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+    let (router, _dir) = app(&["--probe-registry", &down]);
+    let (status, _, error) = post_error(router, "/hook", &[]).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert!(error.starts_with("unreachable registry: "), "{}", error);
+
+    let (router, _dir) = app(&["--admin-token", "admin"]);
+    let auth = [("Authorization", "Bearer admin")];
+    let (status, _, _) = post_error(router.clone(), "/admin/drain", &auth).await;
+    assert!(status.is_success());
+    let (status, headers, error) = post_error(router, "/hook", &[]).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(error, "draining");
+    assert!(headers.contains_key("retry-after"));
+}
@@ -0,0 +1,48 @@
+//! Tests for reusing webhook body buffers.
+
+use podman_autoupdate_hook::buffer::BufferPool;
+
+#[test]
+fn buffers_are_reused_empty() {
+    let pool = BufferPool::new(4, 1024);
+    let mut buffer = pool.take();
+    buffer.extend_from_slice(b"payload");
+    let capacity = buffer.capacity();
+    drop(buffer);
+    assert_eq!(pool.idle(), 1);
+
+    let buffer = pool.take();
+    assert!(buffer.is_empty());
+    assert_eq!(buffer.capacity(), capacity);
+    assert_eq!(pool.idle(), 0);
+}
+
+#[test]
+fn new_buffers_reserve_nothing() {
+    let pool = BufferPool::new(4, 1024);
+    assert_eq!(pool.take().capacity(), 0);
+}
+
+#[test]
+fn large_buffers_are_not_kept() {
+    let pool = BufferPool::new(4, 1024);
+    let mut buffer = pool.take();
+    buffer.extend_from_slice(&[0; 4096]);
+    drop(buffer);
+    assert_eq!(pool.idle(), 0);
+
+    // a reused buffer that grew past the limit is freed too
+    drop(pool.take());
+    let mut buffer = pool.take();
+    buffer.extend_from_slice(&[0; 4096]);
+    drop(buffer);
+    assert_eq!(pool.idle(), 0);
+}
+
+#[test]
+fn at_most_max_buffers_are_kept() {
+    let pool = BufferPool::new(2, 1024);
+    let buffers: Vec<_> = (0..5).map(|_| pool.take()).collect();
+    drop(buffers);
+    assert_eq!(pool.idle(), 2);
+}
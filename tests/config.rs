@@ -0,0 +1,74 @@
+//! Tests for loading the `--config` file.
+
+use podman_autoupdate_hook::{
+    config::{Config, ConfigError},
+    TokenCommand,
+};
+use std::io::Write;
+
+fn load(contents: &str) -> Result<Config, ConfigError> {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(contents.as_bytes()).unwrap();
+    Config::load(file.path())
+}
+
+#[test]
+fn expands_environment_variables() {
+    std::env::set_var("PODMAN_AUTOUPDATE_HOOK_TEST_BEARER", "from-env");
+
+    let config = load(
+        r#"
+        [[endpoints]]
+        name = "blog"
+        auth = { type = "token", bearer = "${PODMAN_AUTOUPDATE_HOOK_TEST_BEARER}" }
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        config.endpoints[0].auth,
+        Some(TokenCommand::Token {
+            bearer: "from-env".to_owned()
+        })
+    );
+}
+
+#[test]
+fn doubled_dollars_are_not_expanded() {
+    let config = load(
+        r#"
+        # was: bearer = "$${PODMAN_AUTOUPDATE_HOOK_TEST_UNSET}"
+        [[endpoints]]
+        name = "blog"
+        auth = { type = "token", bearer = "$${literal}$" }
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        config.endpoints[0].auth,
+        Some(TokenCommand::Token {
+            bearer: "${literal}$".to_owned()
+        })
+    );
+}
+
+#[test]
+fn missing_environment_variables_are_an_error() {
+    let error = load(
+        r#"
+        [[endpoints]]
+        name = "blog"
+        auth = { type = "token", bearer = "${PODMAN_AUTOUPDATE_HOOK_TEST_UNSET}" }
+        "#,
+    )
+    .unwrap_err();
+
+    assert!(
+        error
+            .to_string()
+            .contains("PODMAN_AUTOUPDATE_HOOK_TEST_UNSET"),
+        "{}",
+        error
+    );
+}
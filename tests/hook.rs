@@ -0,0 +1,963 @@
+//! End-to-end tests that run the server against a fake `podman` script.
+
+use axum::response::IntoResponse;
+use hyper::{body::Bytes, Body, Client, Method, Request, StatusCode};
+use std::{
+    net::TcpListener,
+    os::unix::fs::PermissionsExt,
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tempfile::TempDir;
+
+const UPDATED: &str = r#"[{"Unit":"web.service","Container":"0123456789ab (web)","Image":"docker.io/library/nginx:latest","ContainerName":"web","ContainerID":"0123456789abcdef","Policy":"registry","Updated":"true"}]"#;
+
+/// Two containers that are already up to date.
+const WEB_AND_DB: &str = r#"[{"Unit":"web.service","Container":"0123456789ab (web)","Image":"docker.io/library/nginx:latest","ContainerName":"web","ContainerID":"0123456789abcdef","Policy":"registry","Updated":"false"},{"Unit":"db.service","Container":"fedcba987654 (db)","Image":"docker.io/library/postgres:16","ContainerName":"db","ContainerID":"fedcba9876543210","Policy":"registry","Updated":"false"}]"#;
+
+/// A running hook server whose `podman` is a shell script.
+struct Hook {
+    child: Child,
+    port: u16,
+    dir: TempDir,
+}
+
+impl Hook {
+    /// Starts the server with a fake podman that runs `script`. Every
+    /// invocation's arguments are appended to `podman.log` in the temp dir.
+    async fn start(script: &str, args: &[&str]) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let podman = dir.path().join("podman");
+        std::fs::write(
+            &podman,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {}\n{}\n",
+                dir.path().join("podman.log").display(),
+                script
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&podman, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let path = format!(
+            "{}:{}",
+            dir.path().display(),
+            std::env::var("PATH").unwrap_or_default()
+        );
+        let child = Command::new(env!("CARGO_BIN_EXE_podman-autoupdate-hook"))
+            .arg("--port")
+            .arg(port.to_string())
+            .arg("--podman-binary")
+            .arg(&podman)
+            // most tests don't care about auth, and those that do add a token
+            .arg("--insecure-no-auth")
+            .args(args)
+            .env("PATH", path)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let hook = Hook { child, port, dir };
+        for _ in 0..100 {
+            if let Ok((StatusCode::OK, _)) = hook.request(Method::GET, "/health", &[]).await {
+                return hook;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("server did not start");
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(StatusCode, Bytes), hyper::Error> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("http://127.0.0.1:{}{}", self.port, path));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+
+        let response = Client::new()
+            .request(request.body(Body::empty()).unwrap())
+            .await?;
+        let status = response.status();
+        Ok((status, hyper::body::to_bytes(response.into_body()).await?))
+    }
+
+    async fn post(&self, headers: &[(&str, &str)]) -> (StatusCode, Bytes) {
+        self.request(Method::POST, "/hook", headers).await.unwrap()
+    }
+
+    fn podman_log(&self) -> String {
+        std::fs::read_to_string(self.dir.path().join("podman.log")).unwrap_or_default()
+    }
+}
+
+impl Drop for Hook {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[tokio::test]
+async fn returns_podman_output() {
+    let hook = Hook::start(&format!("echo '{}'", UPDATED), &[]).await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let expected: serde_json::Value = serde_json::from_str(UPDATED).unwrap();
+    assert_eq!(body, expected);
+    assert_eq!(hook.podman_log(), "auto-update --format json\n");
+}
+
+#[tokio::test]
+async fn snake_case_output_is_parsed() {
+    let output = r#"[{"unit":"web.service","container":"0123456789ab (web)","image":"docker.io/library/nginx:latest","container_name":"web","container_id":"0123456789abcdef","policy":"registry","updated":"true"}]"#;
+    let hook = Hook::start(&format!("echo '{}'", output), &[]).await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let expected: serde_json::Value = serde_json::from_str(UPDATED).unwrap();
+    assert_eq!(body, expected);
+}
+
+#[tokio::test]
+async fn pull_progress_before_the_output_is_skipped() {
+    let script = format!(
+        "echo 'Trying to pull docker.io/library/nginx:latest...'\n\
+         echo 'Getting image source signatures'\n\
+         echo 'Copying blob sha256:0123456789ab'\n\
+         echo '[==========>          ] 12.3MiB / 24.6MiB'\n\
+         echo 'Writing manifest to image destination'\n\
+         echo '{}'",
+        UPDATED
+    );
+    let hook = Hook::start(&script, &[]).await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let expected: serde_json::Value = serde_json::from_str(UPDATED).unwrap();
+    assert_eq!(body, expected);
+}
+
+#[tokio::test]
+async fn malformed_output_is_an_error() {
+    let hook = Hook::start("echo '[{\"Unit\": '", &[]).await;
+
+    let (status, _) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn failing_podman_is_an_error() {
+    let hook = Hook::start("echo 'no such container' >&2\nexit 125", &[]).await;
+
+    let (status, _) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn custom_success_exit_codes_are_parsed() {
+    let script = format!("echo '{}'\nexit 3", UPDATED);
+    let hook = Hook::start(&script, &["--success-exit-codes", "0,3"]).await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, UPDATED);
+
+    let hook = Hook::start(&script, &[]).await;
+    let (status, _) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn rejects_invalid_token() {
+    let hook = Hook::start(&format!("echo '{}'", UPDATED), &["token", "secret"]).await;
+
+    let (status, _) = hook.post(&[("Authorization", "Bearer wrong")]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(hook.podman_log(), "");
+
+    let (status, _) = hook.post(&[("Authorization", "Bearer secret")]).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn head_does_not_update() {
+    let hook = Hook::start(&format!("echo '{}'", UPDATED), &["token", "secret"]).await;
+
+    for method in [Method::HEAD, Method::GET] {
+        let (status, _) = hook.request(method, "/hook", &[]).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+    assert_eq!(hook.podman_log(), "");
+}
+
+/// Sends anonymous `GET /hook` requests until one is rate limited.
+async fn exhaust_rate_limit(hook: &Hook, headers: &[(&str, &str)]) {
+    for _ in 0..50 {
+        let (status, _) = hook.request(Method::GET, "/hook", headers).await.unwrap();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return;
+        }
+    }
+    panic!("requests were never rate limited");
+}
+
+#[tokio::test]
+async fn forwarded_for_is_used_from_trusted_proxies() {
+    let hook = Hook::start("exit 1", &["--trusted-proxy", "127.0.0.1/32"]).await;
+
+    exhaust_rate_limit(&hook, &[("X-Forwarded-For", "192.0.2.1")]).await;
+
+    let (status, _) = hook
+        .request(Method::GET, "/hook", &[("X-Forwarded-For", "192.0.2.2")])
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+
+    // the rightmost untrusted hop is the client, so a spoofed leftmost one is ignored
+    let (status, _) = hook
+        .request(
+            Method::GET,
+            "/hook",
+            &[("X-Forwarded-For", "198.51.100.7, 192.0.2.1, 127.0.0.1")],
+        )
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn forwarded_for_is_ignored_from_untrusted_peers() {
+    let hook = Hook::start("exit 1", &["--trusted-proxy", "10.0.0.0/8"]).await;
+
+    exhaust_rate_limit(&hook, &[("X-Forwarded-For", "192.0.2.1")]).await;
+
+    let (status, _) = hook
+        .request(Method::GET, "/hook", &[("X-Forwarded-For", "192.0.2.2")])
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn updated_values_round_trip() {
+    for value in ["true", "false", "pending", "failed", "rolled back"] {
+        let output = UPDATED.replace(r#""Updated":"true""#, &format!(r#""Updated":"{}""#, value));
+        let hook = Hook::start(&format!("echo '{}'", output), &[]).await;
+
+        let (status, body) = hook.post(&[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(std::str::from_utf8(&body).unwrap(), output);
+    }
+}
+
+#[tokio::test]
+async fn policies_are_parsed() {
+    for (policy, parsed) in [
+        ("registry", "registry"),
+        ("image", "registry"),
+        ("local", "local"),
+        ("something-new", "unknown"),
+    ] {
+        let output = UPDATED.replace(
+            r#""Policy":"registry""#,
+            &format!(r#""Policy":"{}""#, policy),
+        );
+        let hook = Hook::start(&format!("echo '{}'", output), &[]).await;
+
+        let (status, body) = hook.post(&[]).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body[0]["Policy"], parsed);
+    }
+}
+
+#[tokio::test]
+async fn image_details_split_references() {
+    for (image, expected) in [
+        (
+            "docker.io/library/nginx:1.25",
+            r#"{"Registry":"docker.io","Repository":"library/nginx","Tag":"1.25"}"#,
+        ),
+        (
+            "nginx",
+            r#"{"Registry":"docker.io","Repository":"library/nginx","Tag":"latest"}"#,
+        ),
+        (
+            "grafana/grafana:10.0.0",
+            r#"{"Registry":"docker.io","Repository":"grafana/grafana","Tag":"10.0.0"}"#,
+        ),
+        (
+            "ghcr.io/org/app@sha256:abc",
+            r#"{"Registry":"ghcr.io","Repository":"org/app","Digest":"sha256:abc"}"#,
+        ),
+        (
+            "localhost:5000/app:dev@sha256:abc",
+            r#"{"Registry":"localhost:5000","Repository":"app","Tag":"dev","Digest":"sha256:abc"}"#,
+        ),
+    ] {
+        let output = UPDATED.replace("docker.io/library/nginx:latest", image);
+        let hook = Hook::start(&format!("echo '{}'", output), &["--image-details"]).await;
+
+        let (status, body) = hook.post(&[]).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(expected).unwrap();
+        for key in ["Registry", "Repository", "Tag", "Digest"] {
+            assert_eq!(body[0][key], expected[key], "{} of {}", key, image);
+        }
+    }
+}
+
+#[tokio::test]
+async fn force_pull_pulls_before_updating() {
+    let hook = Hook::start(&format!("echo '{}'", UPDATED), &["--force-pull"]).await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body[0]["ForcePulled"], true);
+
+    let log: Vec<_> = hook.podman_log().lines().map(str::to_owned).collect();
+    assert_eq!(
+        log,
+        [
+            "auto-update --format json --dry-run",
+            "pull docker.io/library/nginx:latest",
+            "auto-update --format json",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn dry_run_only_reports_updates() {
+    let pending = UPDATED.replace(r#""Updated":"true""#, r#""Updated":"pending""#);
+    let hook = Hook::start(
+        &format!("echo '{}'", pending),
+        &["--dry-run", "--force-pull"],
+    )
+    .await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body[0]["Updated"], "pending");
+
+    // nothing is pulled, even with --force-pull
+    assert_eq!(hook.podman_log(), "auto-update --format json --dry-run\n");
+}
+
+#[tokio::test]
+async fn updates_run_one_at_a_time() {
+    let script = format!(
+        "sleep 0.2\necho done >> \"$(dirname \"$0\")/podman.log\"\necho '{}'",
+        UPDATED
+    );
+    let hook = Hook::start(&script, &[]).await;
+
+    let (first, second) = tokio::join!(hook.post(&[]), hook.post(&[]));
+    assert_eq!(first.0, StatusCode::OK);
+    assert_eq!(second.0, StatusCode::OK);
+    assert_eq!(
+        hook.podman_log(),
+        "auto-update --format json\ndone\nauto-update --format json\ndone\n"
+    );
+}
+
+#[tokio::test]
+async fn bursts_of_webhooks_are_coalesced() {
+    let hook = Hook::start(
+        &format!("echo '{}'", UPDATED),
+        &["--coalesce-window", "300ms"],
+    )
+    .await;
+
+    let (first, second, third) = tokio::join!(hook.post(&[]), hook.post(&[]), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        hook.post(&[]).await
+    });
+    for (status, body) in [&first, &second, &third] {
+        assert_eq!(*status, StatusCode::OK);
+        assert_eq!(body, &first.1);
+    }
+    assert_eq!(hook.podman_log(), "auto-update --format json\n");
+
+    // the next webhook starts a new batch
+    assert_eq!(hook.post(&[]).await.0, StatusCode::OK);
+    assert_eq!(hook.podman_log().lines().count(), 2);
+}
+
+#[tokio::test]
+async fn coalesced_skips_count_as_skipped() {
+    let hook = Hook::start(
+        &format!("echo '{}'", UPDATED),
+        &["--metrics", "--coalesce-window", "50ms", "--cooldown", "1h"],
+    )
+    .await;
+    assert_eq!(hook.post(&[]).await.0, StatusCode::OK);
+    // the next batch runs inside the cooldown
+    let (_, body) = hook.post(&[]).await;
+    assert_eq!(body, r#"{"status":"skipped"}"#);
+
+    let (_, body) = hook.request(Method::GET, "/metrics", &[]).await.unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    for line in [
+        "podman_autoupdate_hook_webhooks_total{outcome=\"success\"} 1",
+        "podman_autoupdate_hook_webhooks_total{outcome=\"skipped\"} 1",
+    ] {
+        assert!(
+            metrics.lines().any(|l| l == line),
+            "{} in\n{}",
+            line,
+            metrics
+        );
+    }
+}
+
+#[tokio::test]
+async fn metrics_count_webhooks_and_updates() {
+    let hook = Hook::start(
+        &format!("echo '{}'", UPDATED),
+        &["--metrics", "token", "secret"],
+    )
+    .await;
+    assert_eq!(hook.post(&[]).await.0, StatusCode::UNAUTHORIZED);
+    let authorized = [("Authorization", "Bearer secret")];
+    assert_eq!(hook.post(&authorized).await.0, StatusCode::OK);
+
+    // scrapes aren't rate limited
+    for _ in 0..10 {
+        let (status, _) = hook.request(Method::GET, "/metrics", &[]).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (_, body) = hook.request(Method::GET, "/metrics", &[]).await.unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    for line in [
+        "podman_autoupdate_hook_webhooks_total{outcome=\"success\"} 1",
+        "podman_autoupdate_hook_webhooks_total{outcome=\"unauthorized\"} 1",
+        "podman_autoupdate_hook_webhooks_total{outcome=\"skipped\"} 0",
+        "podman_autoupdate_hook_update_duration_seconds_count 1",
+        "podman_autoupdate_hook_update_duration_seconds_bucket{le=\"+Inf\"} 1",
+        "podman_autoupdate_hook_containers_updated_total 1",
+    ] {
+        assert!(
+            metrics.lines().any(|l| l == line),
+            "{} in\n{}",
+            line,
+            metrics
+        );
+    }
+}
+
+#[tokio::test]
+async fn failed_updates_have_an_error_body() {
+    let hook = Hook::start("exit 1", &["--breaker-threshold", "1"]).await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body, r#"{"error":"update failed"}"#);
+
+    // the failure opened the breaker
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body, r#"{"error":"circuit breaker is open"}"#);
+}
+
+/// Serves the parts of the libpod api the hook uses: `web` has a new local
+/// image, and comes back from a restart running `restarted`, and `db` is
+/// pinned to the digest its registry has. Returns the url and every request
+/// it got.
+async fn fake_libpod(restarted: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let log = requests.clone();
+    let app = axum::Router::new().route(
+        "/v4.0.0/libpod/*path",
+        axum::routing::any(move |method: Method, uri: axum::http::Uri| {
+            let log = log.clone();
+            async move {
+                let path = uri.path().trim_start_matches("/v4.0.0/libpod");
+                log.lock().unwrap().push(format!("{} {}", method, path));
+                match path {
+                    "/containers/json" => r#"[{"Id":"0123456789abcdef","Names":["web"],"Image":"localhost/web:latest","ImageID":"old","Labels":{"io.containers.autoupdate":"local","PODMAN_SYSTEMD_UNIT":"web.service"}},{"Id":"fedcba9876543210","Names":["db"],"Image":"localhost:5000/db@sha256:abc","ImageID":"db","Labels":{"io.containers.autoupdate":"registry"}}]"#.into_response(),
+                    "/images/localhost/web:latest/json" => r#"{"Id":"new"}"#.into_response(),
+                    "/images/localhost:5000/db@sha256:abc/json" => {
+                        r#"{"Id":"db","RepoDigests":["localhost:5000/db@sha256:abc"]}"#.into_response()
+                    }
+                    "/images/pull" => r#"{"id":"db"}"#.into_response(),
+                    "/containers/0123456789abcdef/restart" => StatusCode::NO_CONTENT.into_response(),
+                    "/containers/0123456789abcdef/json" => {
+                        format!(r#"{{"Image":"{}"}}"#, restarted).into_response()
+                    }
+                    _ => StatusCode::NOT_FOUND.into_response(),
+                }
+            }
+        }),
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(app.into_make_service());
+    tokio::spawn(server);
+    (url, requests)
+}
+
+#[tokio::test]
+async fn api_restarts_containers_with_a_new_image() {
+    let (url, requests) = fake_libpod("new").await;
+    let hook = Hook::start("", &["--podman-url", &url]).await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body[0]["ContainerName"], "web");
+    assert_eq!(body[0]["Updated"], "true");
+    assert_eq!(body[1]["Updated"], "false");
+    let requests = requests.lock().unwrap();
+    assert!(requests.contains(&"POST /containers/0123456789abcdef/restart".to_owned()));
+    assert!(!requests.iter().any(|r| r.contains("fedcba9876543210")));
+}
+
+#[tokio::test]
+async fn api_restart_with_the_old_image_isnt_an_update() {
+    let (url, requests) = fake_libpod("old").await;
+    let hook = Hook::start("", &["--podman-url", &url]).await;
+
+    let response = Client::new()
+        .request(
+            Request::post(format!("http://127.0.0.1:{}/hook", hook.port))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["X-Updated-Count"], "0");
+
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body[0]["Updated"], "pending");
+    assert!(requests
+        .lock()
+        .unwrap()
+        .contains(&"POST /containers/0123456789abcdef/restart".to_owned()));
+}
+
+#[tokio::test]
+async fn api_warmup_pulls_and_restarts_nothing() {
+    let (url, requests) = fake_libpod("new").await;
+    let _hook = Hook::start("", &["--podman-url", &url, "--warmup"]).await;
+
+    let requests = requests.lock().unwrap();
+    assert!(requests.contains(&"GET /containers/json".to_owned()));
+    assert!(
+        !requests.iter().any(|r| r.starts_with("POST ")),
+        "{:?}",
+        requests
+    );
+}
+
+/// Posts an empty body with the signature github would send for it.
+async fn post_signed(hook: &Hook, secret: &str) -> StatusCode {
+    use hmac::{Hmac, Mac};
+    // the hook posts an empty body
+    let mac = Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+    hook.post(&[("X-Hub-Signature-256", &signature)]).await.0
+}
+
+#[tokio::test]
+async fn keyring_is_reloaded_on_hangup() {
+    let keys = tempfile::tempdir().unwrap();
+    let keyring = keys.path().join("keyring");
+    std::fs::write(&keyring, "old\n\nother\n").unwrap();
+    let hook = Hook::start(
+        &format!("echo '{}'", UPDATED),
+        &[
+            "github",
+            "primary",
+            "--secret-keyring",
+            keyring.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    assert_eq!(post_signed(&hook, "primary").await, StatusCode::OK);
+    assert_eq!(post_signed(&hook, "old").await, StatusCode::OK);
+    assert_eq!(post_signed(&hook, "new").await, StatusCode::UNAUTHORIZED);
+
+    std::fs::write(&keyring, "new\n").unwrap();
+    let killed = Command::new("kill")
+        .arg("-HUP")
+        .arg(hook.child.id().to_string())
+        .status()
+        .unwrap();
+    assert!(killed.success());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(post_signed(&hook, "new").await, StatusCode::OK);
+    assert_eq!(post_signed(&hook, "old").await, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn filter_label_restricts_the_update() {
+    let script = format!(
+        "case \"$1\" in\nps) echo web ;;\n*) echo '{}' ;;\nesac",
+        WEB_AND_DB
+    );
+    let hook = Hook::start(&script, &["--filter-label", "deploy.group=web"]).await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let names: Vec<_> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| c["ContainerName"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["web"]);
+    assert!(hook
+        .podman_log()
+        .contains("ps --format {{.Names}} --filter label=deploy.group=web"));
+}
+
+#[tokio::test]
+async fn skipped_containers_are_reported() {
+    let script = format!(
+        "case \"$1\" in\nps) echo web ;;\n*) echo '{}' ;;\nesac",
+        WEB_AND_DB
+    );
+    let hook = Hook::start(
+        &script,
+        &["--filter-label", "deploy.group=web", "--report-skipped"],
+    )
+    .await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["updated"][0]["ContainerName"], "web");
+    assert_eq!(
+        body["skipped"],
+        serde_json::json!([{ "container": "db", "reason": "label-filter" }])
+    );
+}
+
+#[tokio::test]
+async fn pretty_responses_keep_field_order() {
+    let hook = Hook::start(&format!("echo '{}'", UPDATED), &["--pretty"]).await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::OK);
+    let expected: serde_json::Value = serde_json::from_str(UPDATED).unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains('\n'));
+    // the same fields in the same order, only with whitespace added
+    let strip = |s: &str| s.split_whitespace().collect::<String>();
+    assert_eq!(strip(&body), strip(UPDATED));
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn once_prints_only_json_to_stdout() {
+    let dir = tempfile::tempdir().unwrap();
+    let podman = dir.path().join("podman");
+    // the progress on stderr is what podman prints while pulling
+    let script = format!("#!/bin/sh\necho 'Copying blob' >&2\necho '{}'\n", UPDATED);
+    std::fs::write(&podman, script).unwrap();
+    std::fs::set_permissions(&podman, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_podman-autoupdate-hook"))
+        .arg("--once")
+        .arg("--podman-binary")
+        .arg(&podman)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.trim_end(), UPDATED);
+    serde_json::from_str::<serde_json::Value>(&stdout).unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("stderr: Copying blob"));
+}
+
+#[tokio::test]
+async fn falls_back_to_table_output_without_json_support() {
+    let script = "if [ \"$2\" = --format ]; then\n\
+        echo 'Error: unknown flag: --format' >&2\n\
+        exit 125\n\
+        fi\n\
+        echo 'UNIT         CONTAINER           IMAGE                           POLICY    UPDATED'\n\
+        echo 'web.service  0123456789ab (web)  docker.io/library/nginx:latest  registry  true'";
+    let hook = Hook::start(script, &[]).await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body[0]["ContainerName"], "web");
+    assert_eq!(body[0]["Updated"], "true");
+    assert_eq!(
+        hook.podman_log(),
+        "auto-update --format json\nauto-update\n"
+    );
+}
+
+#[tokio::test]
+async fn only_allowed_units_are_reported() {
+    for (patterns, expected) in [
+        (&["web.service"][..], &["web"][..]),
+        (&["web*"], &["web"]),
+        (&["web", "db.service"], &["db"]),
+        (&["*"], &["web", "db"]),
+    ] {
+        let args: Vec<_> = patterns.iter().flat_map(|p| ["--allow-unit", p]).collect();
+        let hook = Hook::start(&format!("echo '{}'", WEB_AND_DB), &args).await;
+
+        let (status, body) = hook.post(&[]).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let names: Vec<_> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["ContainerName"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, expected, "{:?}", patterns);
+    }
+}
+
+#[tokio::test]
+async fn host_info_wraps_the_response() {
+    let hook = Hook::start(&format!("echo '{}'", UPDATED), &["--host-info"]).await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(!body["hostname"].as_str().unwrap().is_empty());
+    assert!(body.get("machine_id").is_none());
+    assert_eq!(
+        body["updated"],
+        serde_json::from_str::<serde_json::Value>(UPDATED).unwrap()
+    );
+    assert_eq!(body["truncated"], false);
+}
+
+#[tokio::test]
+async fn responses_follow_the_accept_header() {
+    let hook = Hook::start(&format!("echo '{}'", UPDATED), &[]).await;
+
+    for accept in [
+        "application/json",
+        "*/*",
+        "text/html, application/json;q=0.5",
+    ] {
+        let (status, body) = hook.post(&[("Accept", accept)]).await;
+        assert_eq!(status, StatusCode::OK, "{}", accept);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::from_str::<serde_json::Value>(UPDATED).unwrap(),
+            "{}",
+            accept
+        );
+    }
+
+    let (status, body) = hook.post(&[("Accept", "text/plain")]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "web\tdocker.io/library/nginx:latest\ttrue\n");
+
+    let (status, body) = hook
+        .post(&[("Accept", "text/plain;version=0.0.4;q=0.9, */*;q=0.1")])
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("podman_autoupdate_containers{updated=\"true\"} 1\n"));
+    assert!(body.contains(
+        "podman_autoupdate_container{container=\"web\",image=\"docker.io/library/nginx:latest\",updated=\"true\"} 1\n"
+    ));
+}
+
+#[tokio::test]
+async fn a_second_instance_is_refused_the_lock() {
+    let lock = tempfile::NamedTempFile::new().unwrap();
+    let lock = lock.path().to_str().unwrap();
+    let _hook = Hook::start("echo '[]'", &["--lock-file", lock]).await;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_podman-autoupdate-hook"))
+        .args(["--once", "--lock-file", lock])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("another instance (pid "), "{}", stderr);
+}
+
+#[tokio::test]
+async fn passthrough_returns_podman_output_verbatim() {
+    // a field this hook doesn't know and a value it can't parse
+    let output = r#"[ {"Unit": "web.service", "Updated": "maybe", "Future": 1} ]"#;
+    let hook = Hook::start(&format!("echo '{}'", output), &["--passthrough"]).await;
+
+    let (status, body) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, format!("{}\n", output));
+    assert!(hook.podman_log().contains("auto-update --format json"));
+
+    let (status, _) = hook.post(&[("Accept", "text/plain")]).await;
+    assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+}
+
+#[tokio::test]
+async fn discord_notifications_list_updated_containers() {
+    use hyper::service::{make_service_fn, service_fn};
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let discord =
+        hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |request: Request<Body>| {
+                    let tx = tx.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await?;
+                        let _ = tx.send(body);
+                        Ok::<_, hyper::Error>(hyper::Response::new(Body::empty()))
+                    }
+                }))
+            }
+        }));
+    let url = format!("http://{}/api/webhooks/1/token", discord.local_addr());
+    tokio::spawn(discord);
+
+    let hook = Hook::start(&format!("echo '{}'", UPDATED), &["--discord-webhook", &url]).await;
+    assert_eq!(hook.post(&[]).await.0, StatusCode::OK);
+
+    let body = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let embed = &body["embeds"][0];
+    assert_eq!(embed["description"], "Updated 1 container: web");
+    assert_eq!(embed["fields"][0]["name"], "web");
+    assert_eq!(
+        embed["fields"][0]["value"],
+        "docker.io/library/nginx:latest"
+    );
+}
+
+#[tokio::test]
+async fn unhealthy_containers_are_a_partial_success() {
+    for (health, status) in [
+        ("healthy", StatusCode::OK),
+        ("unhealthy", StatusCode::MULTI_STATUS),
+    ] {
+        let script = format!(
+            "case \"$1\" in\n  container) echo {} ;;\n  *) echo '{}' ;;\nesac",
+            health, UPDATED
+        );
+        let hook = Hook::start(&script, &["--verify-health"]).await;
+
+        let (got, body) = hook.post(&[]).await;
+        assert_eq!(got, status, "{}", health);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body[0]["Health"], health);
+        assert!(hook
+            .podman_log()
+            .contains("container inspect --format {{.State.Health.Status}} web"));
+    }
+}
+
+#[tokio::test]
+async fn serves_hooks_on_a_single_thread() {
+    let hook = Hook::start(&format!("echo '{}'", UPDATED), &["--current-thread"]).await;
+    assert_eq!(hook.post(&[]).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn exits_after_the_idle_timeout() {
+    let mut hook = Hook::start("echo '[]'", &["--idle-timeout", "1s"]).await;
+    assert_eq!(hook.post(&[]).await.0, StatusCode::OK);
+
+    for _ in 0..100 {
+        if let Some(status) = hook.child.try_wait().unwrap() {
+            assert!(status.success());
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("server did not exit");
+}
+
+#[tokio::test]
+async fn status_says_whether_anything_changed() {
+    let args = ["--changed-status", "202", "--unchanged-status", "204"];
+    for (output, status) in [
+        (UPDATED, StatusCode::ACCEPTED),
+        (WEB_AND_DB, StatusCode::NO_CONTENT),
+    ] {
+        let hook = Hook::start(&format!("echo '{}'", output), &args).await;
+        let (got, body) = hook.post(&[]).await;
+        assert_eq!(got, status);
+        assert_eq!(body.is_empty(), status == StatusCode::NO_CONTENT);
+    }
+}
+
+#[tokio::test]
+async fn failed_updates_are_not_changes() {
+    let failed = UPDATED.replace(r#""Updated":"true""#, r#""Updated":"failed""#);
+    let hook = Hook::start(&format!("echo '{}'", failed), &["--changed-status", "202"]).await;
+    let response = Client::new()
+        .request(
+            Request::post(format!("http://127.0.0.1:{}/hook", hook.port))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["X-Changed-Count"], "0");
+
+    let hook = Hook::start(&format!("echo '{}'", failed), &["--fail-on-no-update"]).await;
+    let (status, _) = hook.post(&[]).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn repeated_idempotency_keys_are_replayed() {
+    let hook = Hook::start(&format!("echo '{}'", UPDATED), &[]).await;
+
+    let first = hook.post(&[("Idempotency-Key", "deploy-1")]).await;
+    let repeat = hook.post(&[("Idempotency-Key", "deploy-1")]).await;
+    assert_eq!(first.0, StatusCode::OK);
+    assert_eq!(repeat, first);
+    assert_eq!(hook.podman_log().lines().count(), 1);
+
+    let other = hook.post(&[("Idempotency-Key", "deploy-2")]).await;
+    assert_eq!(other.0, StatusCode::OK);
+    assert_eq!(hook.podman_log().lines().count(), 2);
+}
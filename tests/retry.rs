@@ -0,0 +1,48 @@
+//! Tests for the backoff between retried updates.
+
+use std::time::Duration;
+
+use podman_autoupdate_hook::Retry;
+use rand::{rngs::StdRng, SeedableRng};
+
+fn retry(seed: u64) -> Retry {
+    Retry {
+        retries: 10,
+        delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(5),
+        seed: Some(seed),
+    }
+}
+
+#[test]
+fn delays_stay_within_the_backoff() {
+    let retry = retry(0);
+    let mut rng = StdRng::seed_from_u64(42);
+    for attempt in 0..12 {
+        let ceiling = (retry.delay * 2u32.pow(attempt)).min(retry.max_delay);
+        for _ in 0..100 {
+            let delay = retry.backoff(attempt, &mut rng);
+            assert!(delay <= ceiling, "{:?} > {:?}", delay, ceiling);
+        }
+    }
+}
+
+#[test]
+fn huge_attempts_are_capped() {
+    let retry = retry(0);
+    let mut rng = StdRng::seed_from_u64(42);
+    assert!(retry.backoff(u32::MAX, &mut rng) <= retry.max_delay);
+}
+
+#[test]
+fn a_seed_repeats_the_delays() {
+    let delays = |seed| {
+        let retry = retry(seed);
+        let mut rng = retry.rng();
+        (0..5)
+            .map(|attempt| retry.backoff(attempt, &mut rng))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(delays(7), delays(7));
+    assert_ne!(delays(7), delays(8));
+}
@@ -0,0 +1,36 @@
+//! Tests for checking github signatures against several secrets.
+
+use podman_autoupdate_hook::signature::Verifier;
+
+/// The example from github's "validating webhook deliveries" docs.
+const SECRET: &str = "It's a Secret to Everybody";
+const SIGNATURE: &str = "757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+fn verify(secrets: &[&str], signature: &str) -> Option<usize> {
+    let mut verifier = Verifier::new(secrets);
+    // split, as the body streams in
+    verifier.update(b"Hello, ");
+    verifier.update(b"World!");
+    verifier.verify(signature)
+}
+
+#[test]
+fn matches_the_secret_that_signed_the_body() {
+    assert_eq!(verify(&[SECRET], SIGNATURE), Some(0));
+    assert_eq!(verify(&["old", SECRET], SIGNATURE), Some(1));
+    assert_eq!(verify(&["old", "older"], SIGNATURE), None);
+    assert_eq!(verify(&[], SIGNATURE), None);
+}
+
+#[test]
+fn malformed_signatures_match_nothing() {
+    for signature in [
+        "",
+        &SIGNATURE[..10],
+        &SIGNATURE[..63],
+        &format!("{}00", SIGNATURE),
+        &SIGNATURE.replace('7', "z"),
+    ] {
+        assert_eq!(verify(&[SECRET], signature), None, "{:?}", signature);
+    }
+}